@@ -1,25 +1,39 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
-use std::task::Context;
-use std::thread;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{pin, Pin};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::thread::{self, Thread};
+use std::time::Duration;
 
-use crossbeam::channel;
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
 use futures::channel::oneshot;
 use futures::future::BoxFuture;
 use futures::task::{waker_ref, ArcWake};
-use futures::Future;
+use futures::{Future, FutureExt};
 use once_cell::sync::Lazy;
 
-static QUEUE: Lazy<channel::Sender<Arc<Task>>> = Lazy::new(|| {
-    let (sender, receiver) = channel::unbounded::<Arc<Task>>();
-    for _ in 0..num_cpus::get().max(1) {
-        let receiver = receiver.clone();
-        thread::spawn(move || receiver.iter().for_each(|task| task.run()));
-    }
-    sender
+/// The pool used by the free [`spawn`], it is started on first use and never shut down.
+static DEFAULT_POOL: Lazy<ThreadPool> = Lazy::new(|| {
+    Builder::new()
+        .build()
+        .expect("failed to start the default thread pool")
 });
 
-type JoinHandler<R> = BoxFuture<'static, R>;
+type Hook = Arc<dyn Fn() + Send + Sync>;
+
+thread_local! {
+    // the local queue of the current thread, if it is a worker of some pool
+    static LOCAL: RefCell<Option<Local>> = const { RefCell::new(None) };
+}
 
 const WOKEN: usize = 0b01;
 const RUNNING: usize = 0b10;
@@ -28,19 +42,43 @@ struct Task {
     // each `Waker` hold a reference to the corresponding task, so the task will shared in
     // different threads, and the `poll` method need a mutable future, so we need use `Mutex` to
     // provide this.
-    future: Mutex<BoxFuture<'static, ()>>,
+    // `None` once the future is completed or dropped by `abort`
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
     state: AtomicUsize,
+    // the pool the task is spawned on, a strong reference would form a cycle with the queues
+    shared: Weak<Shared>,
+    aborted: AtomicBool,
+    // set once the future is `None`, kept apart so `JoinHandle` never contends for its lock
+    finished: AtomicBool,
 }
 
 impl Task {
     fn run(self: Arc<Task>) {
+        // a task is queued at most once, but a worker must not die if that is ever broken, the
+        // worker holding the lock polls it
+        let Ok(mut future) = self.future.try_lock() else {
+            return;
+        };
         let waker = waker_ref(&self);
         self.state.store(RUNNING, Ordering::SeqCst);
         let cx = &mut Context::from_waker(&waker);
-        let poll = self.future.try_lock().unwrap().as_mut().poll(cx);
+
+        let poll = match future.as_mut() {
+            // dropping the future drops the sender of the `JoinHandle`, which sees the abort
+            Some(_) if self.aborted.load(Ordering::SeqCst) => Poll::Ready(()),
+            Some(fut) => fut.as_mut().poll(cx),
+            // a stale wake after completion
+            None => Poll::Ready(()),
+        };
+        if poll.is_ready() {
+            *future = None;
+            self.finished.store(true, Ordering::SeqCst);
+        }
+        drop(future);
+
         if poll.is_pending() && self.state.fetch_and(!RUNNING, Ordering::SeqCst) == WOKEN | RUNNING
         {
-            QUEUE.send(self).unwrap();
+            schedule(self);
         }
     }
 }
@@ -48,29 +86,753 @@ impl Task {
 impl ArcWake for Task {
     fn wake_by_ref(arc_self: &Arc<Self>) {
         if arc_self.state.fetch_or(WOKEN, Ordering::SeqCst) == 0 {
-            QUEUE.send(arc_self.clone()).unwrap();
+            schedule(arc_self.clone());
+        }
+    }
+}
+
+/// Push the task onto the local queue of the current worker if it belongs to the same pool,
+/// otherwise onto the global injector, then wake up an idle worker to run or steal it.
+///
+/// The task is dropped if the pool has been shut down.
+fn schedule(task: Arc<Task>) {
+    let Some(shared) = task.shared.upgrade() else {
+        return;
+    };
+    if shared.shutdown.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let task = LOCAL.with(|local| match &*local.borrow() {
+        Some(local) if Arc::ptr_eq(&local.shared, &shared) => {
+            local.worker.push(task);
+            None
         }
+        _ => Some(task),
+    });
+    if let Some(task) = task {
+        shared.injector.push(task);
     }
+    shared.unpark_one();
+}
+
+struct Shared {
+    injector: Injector<Arc<Task>>,
+    stealers: Vec<Stealer<Arc<Task>>>,
+    sleep: Mutex<()>,
+    condvar: Condvar,
+    // the workers about to sleep or sleeping, so `schedule` only takes the lock if needed
+    sleepers: AtomicUsize,
+    shutdown: AtomicBool,
 }
 
-pub fn spawn<F, R>(future: F) -> JoinHandler<R>
+impl Shared {
+    fn has_work(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+
+    fn unpark_one(&self) {
+        // pairs with the fence in `park`: either the worker sees the task, or we see the worker
+        atomic::fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _sleep = self.sleep.lock().unwrap();
+            self.condvar.notify_one();
+        }
+    }
+
+    /// sleep on the condvar until a task is scheduled or the pool shuts down.
+    fn park(&self) {
+        let sleep = self.sleep.lock().unwrap();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        if !self.has_work() && !self.shutdown.load(Ordering::SeqCst) {
+            drop(self.condvar.wait(sleep).unwrap());
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Local {
+    index: usize,
+    worker: Worker<Arc<Task>>,
+    shared: Arc<Shared>,
+}
+
+impl Local {
+    fn find_task(&self) -> Option<Arc<Task>> {
+        if let Some(task) = self.worker.pop() {
+            return Some(task);
+        }
+
+        let shared = &self.shared;
+        loop {
+            let mut retry = false;
+
+            // steal from the injector first, then from siblings starting after ourselves
+            let siblings = (1..shared.stealers.len())
+                .map(|i| &shared.stealers[(self.index + i) % shared.stealers.len()]);
+            let steals = iter::once(shared.injector.steal_batch_and_pop(&self.worker))
+                .chain(siblings.map(|s| s.steal_batch_and_pop(&self.worker)));
+
+            for steal in steals {
+                match steal {
+                    Steal::Success(task) => return Some(task),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+            }
+
+            if !retry {
+                return None;
+            }
+        }
+    }
+}
+
+/// Create a task running `future` on the pool of `shared`, and the handle to await its output.
+///
+/// The `guard` of a scope is released only after the future and its output not taken by the
+/// handle are dropped.
+///
+/// # Safety
+///
+/// The future and its output must be dropped before `'a` ends, which the scope of `guard`
+/// waits for.
+unsafe fn new_task<'a, F, R>(
+    future: F,
+    shared: Weak<Shared>,
+    guard: Option<ScopeGuard>,
+) -> (Arc<Task>, JoinHandle<R>)
 where
-    F: Future<Output = R> + Send + 'static,
-    R: Send + 'static,
+    F: Future<Output = R> + Send + 'a,
+    R: Send + 'a,
 {
     let (s, r) = oneshot::channel();
 
-    // use oneshot channel to get future result to `JoinHandler`
+    // use oneshot channel to get future result to `JoinHandle`, the output is dropped here if
+    // the handle is gone
     let future = async move {
-        let _ = s.send(future.await);
+        let output = AssertUnwindSafe(future).catch_unwind().await;
+        let _ = s.send(output);
+    };
+    let future: BoxFuture<'a, ()> = match guard {
+        Some(guard) => Box::pin(ScopedFuture {
+            future,
+            _guard: guard,
+        }),
+        None => Box::pin(future),
     };
+    // SAFETY: only the lifetime changes, the caller guarantees the future doesn't outlive it.
+    let future = mem::transmute::<BoxFuture<'a, ()>, BoxFuture<'static, ()>>(future);
 
     let task = Arc::new(Task {
-        future: Mutex::new(Box::pin(future)),
-        state: AtomicUsize::default(),
+        future: Mutex::new(Some(future)),
+        // it is queued right away
+        state: AtomicUsize::new(WOKEN),
+        shared,
+        aborted: AtomicBool::new(false),
+        finished: AtomicBool::new(false),
     });
+    let handle = JoinHandle {
+        task: Arc::downgrade(&task),
+        output: r,
+    };
+    (task, handle)
+}
+
+/// Run tasks until the pool shuts down and the queues are drained.
+fn run_worker(local: Local) {
+    let shared = local.shared.clone();
+    LOCAL.with(|l| *l.borrow_mut() = Some(local));
+
+    loop {
+        // the borrow is released before running the task, which may schedule tasks
+        let task = LOCAL.with(|l| l.borrow().as_ref().unwrap().find_task());
+        match task {
+            Some(task) => task.run(),
+            None if shared.shutdown.load(Ordering::SeqCst) => break,
+            None => shared.park(),
+        }
+    }
+
+    // the tasks left were scheduled after the shutdown and would never run
+    let local = LOCAL.with(|l| l.borrow_mut().take());
+    drop(local);
+}
+
+/// The threads running the closures of [`ThreadPool::spawn_blocking`], started on demand and
+/// stopped after being idle for `keep_alive`.
+struct Blocking {
+    state: Mutex<BlockingState>,
+    condvar: Condvar,
+    max_threads: usize,
+    keep_alive: Duration,
+    thread_name: String,
+    stack_size: Option<usize>,
+    on_thread_start: Option<Hook>,
+    on_thread_stop: Option<Hook>,
+}
+
+#[derive(Default)]
+struct BlockingState {
+    queue: VecDeque<Arc<Task>>,
+    threads: usize,
+    idle: usize,
+    // the idle threads notified but not woken yet, they are no longer counted in `idle`
+    notified: usize,
+    shutdown: bool,
+}
 
-    QUEUE.send(task).unwrap();
+impl Blocking {
+    fn push(self: &Arc<Self>, task: Arc<Task>) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.push_back(task);
+        if state.idle > 0 {
+            state.idle -= 1;
+            state.notified += 1;
+            self.condvar.notify_one();
+            return;
+        }
+        if state.threads == self.max_threads {
+            return;
+        }
+        state.threads += 1;
+        drop(state);
+
+        let blocking = self.clone();
+        let mut builder = thread::Builder::new().name(format!("{}-blocking", self.thread_name));
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        if builder.spawn(move || blocking.run()).is_err() {
+            let mut state = self.state.lock().unwrap();
+            state.threads -= 1;
+            // nobody would run the closures, dropping them cancels their handles
+            if state.threads == 0 {
+                state.queue.clear();
+            }
+        }
+    }
+
+    fn run(&self) {
+        if let Some(f) = &self.on_thread_start {
+            f();
+        }
+
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(task) = state.queue.pop_front() {
+                drop(state);
+                task.run();
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            if state.shutdown {
+                break;
+            }
+
+            state.idle += 1;
+            let (guard, timeout) = self.condvar.wait_timeout(state, self.keep_alive).unwrap();
+            state = guard;
+            if state.notified > 0 {
+                state.notified -= 1;
+                continue;
+            }
+            state.idle -= 1;
+            if timeout.timed_out() && state.queue.is_empty() {
+                break;
+            }
+        }
+        state.threads -= 1;
+        drop(state);
+
+        if let Some(f) = &self.on_thread_stop {
+            f();
+        }
+    }
+
+    fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = true;
+        self.condvar.notify_all();
+    }
+}
+
+/// Await the output of a task spawned onto a [`ThreadPool`], dropping the handle detaches the
+/// task.
+pub struct JoinHandle<R> {
+    // a strong reference would keep the future alive after the pool dropped the task
+    task: Weak<Task>,
+    output: oneshot::Receiver<thread::Result<R>>,
+}
+
+impl<R> JoinHandle<R> {
+    /// Cancel the task, it is dropped by a worker the next time it is scheduled, and awaiting the
+    /// handle returns a cancelled [`JoinError`]. It does nothing if the task has completed.
+    pub fn abort(&self) {
+        if let Some(task) = self.task.upgrade() {
+            task.aborted.store(true, Ordering::SeqCst);
+            ArcWake::wake_by_ref(&task);
+        }
+    }
+
+    /// whether the future has completed, panicked, or been dropped.
+    pub fn is_finished(&self) -> bool {
+        self.task
+            .upgrade()
+            .is_none_or(|task| task.finished.load(Ordering::SeqCst))
+    }
+}
+
+impl<R> Future for JoinHandle<R> {
+    type Output = Result<R, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.output.poll_unpin(cx).map(|output| match output {
+            Ok(Ok(r)) => Ok(r),
+            Ok(Err(payload)) => Err(JoinError::Panic(payload)),
+            // the sender is dropped with the future, by `abort` or the pool shutting down
+            Err(_) => Err(JoinError::Cancelled),
+        })
+    }
+}
+
+/// The task of a [`JoinHandle`] didn't complete.
+pub enum JoinError {
+    /// aborted, or dropped because the pool was shut down.
+    Cancelled,
+    /// the future panicked, with the panic payload.
+    Panic(Box<dyn Any + Send + 'static>),
+}
+
+impl JoinError {
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Cancelled)
+    }
+
+    pub fn is_panic(&self) -> bool {
+        matches!(self, Self::Panic(_))
+    }
+
+    /// Returns the panic payload, e.g. to resume the panic with `std::panic::resume_unwind`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task was cancelled.
+    pub fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        self.try_into_panic()
+            .expect("the task was cancelled, not panicked")
+    }
+
+    pub fn try_into_panic(self) -> Result<Box<dyn Any + Send + 'static>, Self> {
+        match self {
+            Self::Panic(payload) => Ok(payload),
+            e => Err(e),
+        }
+    }
+}
+
+/// the message of a panic is a `&str` or a `String`, other payloads can't be shown.
+fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled => f.write_str("Cancelled"),
+            Self::Panic(payload) => f
+                .debug_tuple("Panic")
+                .field(&panic_message(payload.as_ref()).unwrap_or("Any { .. }"))
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled => f.write_str("task was cancelled"),
+            Self::Panic(payload) => match panic_message(payload.as_ref()) {
+                Some(msg) => write!(f, "task panicked: {msg}"),
+                None => f.write_str("task panicked"),
+            },
+        }
+    }
+}
 
-    Box::pin(async { r.await.unwrap() })
+impl std::error::Error for JoinError {}
+
+/// Configure and start a [`ThreadPool`].
+pub struct Builder {
+    threads: usize,
+    thread_name: String,
+    stack_size: Option<usize>,
+    on_thread_start: Option<Hook>,
+    on_thread_stop: Option<Hook>,
+    max_blocking_threads: usize,
+    blocking_keep_alive: Duration,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            threads: num_cpus::get().max(1),
+            thread_name: "best-executor".into(),
+            stack_size: None,
+            on_thread_start: None,
+            on_thread_stop: None,
+            max_blocking_threads: 512,
+            blocking_keep_alive: Duration::from_secs(10),
+        }
+    }
+
+    /// the number of worker threads, defaults to the number of cpus.
+    pub fn threads(mut self, n: usize) -> Self {
+        self.threads = n.max(1);
+        self
+    }
+
+    /// the thread name prefix, the worker index is appended to it.
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// the stack size of the worker threads, defaults to the one of `std::thread`.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// the maximum number of threads running [`ThreadPool::spawn_blocking`] closures, defaults
+    /// to 512.
+    pub fn max_blocking_threads(mut self, n: usize) -> Self {
+        self.max_blocking_threads = n.max(1);
+        self
+    }
+
+    /// how long a blocking thread waits for a new closure before exiting, defaults to 10 seconds.
+    pub fn blocking_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.blocking_keep_alive = keep_alive;
+        self
+    }
+
+    /// run `f` on each worker and blocking thread before it runs any task.
+    pub fn on_thread_start(mut self, f: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_thread_start = Some(Arc::new(f));
+        self
+    }
+
+    /// run `f` on each worker and blocking thread when it stops.
+    pub fn on_thread_stop(mut self, f: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_thread_stop = Some(Arc::new(f));
+        self
+    }
+
+    pub fn build(self) -> io::Result<ThreadPool> {
+        let workers: Vec<_> = (0..self.threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            sleep: Mutex::new(()),
+            condvar: Condvar::new(),
+            sleepers: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+        });
+        let blocking = Arc::new(Blocking {
+            state: Default::default(),
+            condvar: Condvar::new(),
+            max_threads: self.max_blocking_threads,
+            keep_alive: self.blocking_keep_alive,
+            thread_name: self.thread_name.clone(),
+            stack_size: self.stack_size,
+            on_thread_start: self.on_thread_start.clone(),
+            on_thread_stop: self.on_thread_stop.clone(),
+        });
+        let mut pool = ThreadPool {
+            shared: shared.clone(),
+            blocking,
+            workers: Vec::with_capacity(self.threads),
+        };
+
+        for (index, worker) in workers.into_iter().enumerate() {
+            let local = Local {
+                index,
+                worker,
+                shared: shared.clone(),
+            };
+            let on_start = self.on_thread_start.clone();
+            let on_stop = self.on_thread_stop.clone();
+
+            let mut builder = thread::Builder::new().name(format!("{}-{index}", self.thread_name));
+            if let Some(size) = self.stack_size {
+                builder = builder.stack_size(size);
+            }
+            // on error the pool is dropped, which stops the workers already started
+            let worker = builder.spawn(move || {
+                if let Some(f) = on_start {
+                    f();
+                }
+                run_worker(local);
+                if let Some(f) = on_stop {
+                    f();
+                }
+            })?;
+            pool.workers.push(worker);
+        }
+        Ok(pool)
+    }
+}
+
+/// A pool of threads running spawned futures, see [`Builder`].
+///
+/// The pool is shut down when it is dropped.
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    blocking: Arc<Blocking>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Spawn a future onto the pool, its output can be awaited with the returned handle.
+    ///
+    /// A panic of the future is caught and returned by the handle, the worker keeps running.
+    pub fn spawn<F, R>(&self, future: F) -> JoinHandle<R>
+    where
+        F: Future<Output = R> + Send + 'static,
+        R: Send + 'static,
+    {
+        // SAFETY: the future borrows nothing.
+        unsafe { self.spawn_unchecked(future, None) }
+    }
+
+    /// Run `f` with a [`Scope`] to spawn futures borrowing from the caller onto the pool.
+    ///
+    /// It returns after every future spawned in the scope has completed or been dropped. It
+    /// blocks the current thread meanwhile, so it must not be called from a task of the same
+    /// pool, which may need the current worker to make progress.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            data: Arc::new(ScopeData {
+                running: Mutex::new(0),
+                done: Condvar::new(),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // wait for the futures even if `f` panics, they may borrow from its caller
+        let output = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.data.wait();
+        output.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// # Safety
+    ///
+    /// See [`new_task`].
+    unsafe fn spawn_unchecked<'a, F, R>(
+        &self,
+        future: F,
+        guard: Option<ScopeGuard>,
+    ) -> JoinHandle<R>
+    where
+        F: Future<Output = R> + Send + 'a,
+        R: Send + 'a,
+    {
+        let (task, handle) = new_task(future, Arc::downgrade(&self.shared), guard);
+        schedule(task);
+        handle
+    }
+
+    /// Run the blocking closure `f` on a separate set of threads, so the workers running futures
+    /// stay responsive.
+    ///
+    /// A thread is started if none is idle, up to [`Builder::max_blocking_threads`], the closures
+    /// beyond that are queued. Aborting the handle only cancels a closure not started yet.
+    pub fn spawn_blocking<F, R>(&self, f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        // the future is ready at the first poll, and is never scheduled onto the workers
+        // SAFETY: the closure borrows nothing.
+        let (task, handle) = unsafe { new_task(async move { f() }, Weak::new(), None) };
+        self.blocking.push(task);
+        handle
+    }
+
+    /// Stop the workers and wait for them to exit.
+    ///
+    /// The tasks queued before are run first, the tasks woken afterwards are dropped. The blocking
+    /// threads finish the closures queued and exit in the background.
+    pub fn shutdown(self) {
+        drop(self);
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.blocking.shutdown();
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        {
+            let _sleep = self.shared.sleep.lock().unwrap();
+            self.shared.condvar.notify_all();
+        }
+        let current = thread::current().id();
+        for worker in self.workers.drain(..) {
+            // a task shutting down its own pool can't wait for its own thread
+            if worker.thread().id() != current {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+/// Spawn futures borrowing from the caller, see [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    data: Arc<ScopeData>,
+    // invariant lifetimes, like `std::thread::Scope`
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Spawn a future onto the pool, it may borrow anything outliving the scope.
+    pub fn spawn<F, R>(&'scope self, future: F) -> JoinHandle<R>
+    where
+        F: Future<Output = R> + Send + 'scope,
+        R: Send + 'scope,
+    {
+        let guard = ScopeGuard::new(self.data.clone());
+        // SAFETY: `ThreadPool::scope` waits for the guard, which is dropped after the future and
+        // its output.
+        unsafe { self.pool.spawn_unchecked(future, Some(guard)) }
+    }
+}
+
+/// The futures of a scope still alive.
+struct ScopeData {
+    running: Mutex<usize>,
+    done: Condvar,
+}
+
+impl ScopeData {
+    fn wait(&self) {
+        let running = self.running.lock().unwrap();
+        drop(self.done.wait_while(running, |n| *n > 0).unwrap());
+    }
+}
+
+struct ScopeGuard(Arc<ScopeData>);
+
+impl ScopeGuard {
+    fn new(data: Arc<ScopeData>) -> Self {
+        *data.running.lock().unwrap() += 1;
+        Self(data)
+    }
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let mut running = self.0.running.lock().unwrap();
+        *running -= 1;
+        if *running == 0 {
+            self.0.done.notify_all();
+        }
+    }
+}
+
+/// The fields are dropped in order, so the guard is released only after the future is gone.
+struct ScopedFuture<F> {
+    future: F,
+    _guard: ScopeGuard,
+}
+
+impl<F: Future> Future for ScopedFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the future is pinned along with `self`, and never moved out.
+        unsafe { self.map_unchecked_mut(|s| &mut s.future) }.poll(cx)
+    }
+}
+
+/// Run a future to completion on the current thread, parking it while the future is pending.
+///
+/// It blocks the current thread, so it must not be called from a task of a pool.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let unparker = Arc::new(Unparker {
+        thread: thread::current(),
+        notified: AtomicBool::new(false),
+    });
+    let waker = waker_ref(&unparker);
+    let cx = &mut Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return output;
+        }
+        // `park` may return spuriously, and a wake before it makes it return at once
+        while !unparker.notified.swap(false, Ordering::SeqCst) {
+            thread::park();
+        }
+    }
+}
+
+struct Unparker {
+    thread: Thread,
+    notified: AtomicBool,
+}
+
+impl ArcWake for Unparker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.notified.store(true, Ordering::SeqCst);
+        arc_self.thread.unpark();
+    }
+}
+
+/// Spawn futures borrowing from the caller onto the default pool, see [`ThreadPool::scope`].
+pub fn scope<'env, F, T>(f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    DEFAULT_POOL.scope(f)
+}
+
+/// Run a blocking closure on the default pool, see [`ThreadPool::spawn_blocking`].
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    DEFAULT_POOL.spawn_blocking(f)
+}
+
+/// Spawn a future onto the default pool, which has a thread per cpu.
+pub fn spawn<F, R>(future: F) -> JoinHandle<R>
+where
+    F: Future<Output = R> + Send + 'static,
+    R: Send + 'static,
+{
+    DEFAULT_POOL.spawn(future)
 }
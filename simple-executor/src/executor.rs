@@ -1,7 +1,12 @@
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::sync::{Arc, Mutex};
-use std::task::Context;
+use std::fmt;
+use std::pin::{pin, Pin};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use futures::channel::oneshot;
 use futures::task::waker_ref;
 use futures::{Future, FutureExt};
 
@@ -12,30 +17,176 @@ pub struct Spawner {
 }
 
 impl Spawner {
-    pub fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
-        let future = future.boxed();
+    /// Spawn a task, its output can be awaited with the returned [`JoinHandle`].
+    pub fn spawn<F, T>(&self, future: F) -> JoinHandle<T>
+    where
+        F: Future<Output = T> + 'static + Send,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+
+        // use oneshot channel to get future output to `JoinHandle`, the receiver may be dropped
+        let future = async move {
+            let _ = sender.send(future.await);
+        }
+        .boxed();
         let task = Arc::new(Task::new(
             Mutex::new(Some(future)),
             self.sync_sender.clone(),
         ));
+        let weak = Arc::downgrade(&task);
         self.sync_sender.send(task).expect("too many tasks queued");
+
+        JoinHandle {
+            task: weak,
+            output: receiver,
+        }
     }
 }
 
+/// Await the output of a task spawned with [`Spawner::spawn`], dropping the handle detaches the
+/// task.
+pub struct JoinHandle<T> {
+    // a strong reference would keep the sender of the task alive, and `Executor::run` running
+    task: Weak<Task>,
+    output: oneshot::Receiver<T>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Cancel the task, it is dropped by the executor the next time it is scheduled, and awaiting
+    /// the handle returns `Err(Aborted)`. It does nothing if the task has completed.
+    pub fn abort(&self) {
+        if let Some(task) = self.task.upgrade() {
+            task.aborted.store(true, Ordering::Release);
+            // the executor may be gone, then nobody would poll the task anyway
+            let _ = task.sync_sender.send(task.clone());
+        }
+    }
+
+    /// whether the task has completed or has been dropped, e.g. by the executor after `abort`.
+    pub fn is_finished(&self) -> bool {
+        self.task
+            .upgrade()
+            .is_none_or(|task| task.finished.load(Ordering::Acquire))
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Aborted>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // the sender is dropped without sending only if the future is dropped unfinished
+        self.output.poll_unpin(cx).map_err(|_| Aborted)
+    }
+}
+
+/// The task of a [`JoinHandle`] was aborted, or dropped with the executor, before it completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the task was aborted before it completed")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
 pub struct Executor {
     receiver: Receiver<Arc<Task>>,
+    // wakes `run_until`, taken by `run` so it returns once every spawner and task is dropped
+    sync_sender: Mutex<Option<SyncSender<Arc<Task>>>>,
 }
 
 impl Executor {
+    /// Run the tasks until every [`Spawner`] and task is dropped.
     pub fn run(&self) {
+        drop(self.sync_sender.lock().unwrap().take());
         while let Ok(task) = self.receiver.recv() {
-            let mut future_guard = task.future.lock().unwrap();
-            if let Some(mut future) = future_guard.take() {
-                let waker = waker_ref(&task);
-                let cx = &mut Context::from_waker(&waker);
-                if future.as_mut().poll(cx).is_pending() {
-                    *future_guard = Some(future);
+            self.run_task(task);
+        }
+    }
+
+    /// Run a task if one is queued, returns whether it did, it never blocks.
+    pub fn try_run_one(&self) -> bool {
+        match self.receiver.try_recv() {
+            Ok(task) => {
+                self.run_task(task);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Run the tasks until none is queued, the tasks waiting for a timer or another thread stay
+    /// pending.
+    pub fn run_until_stalled(&self) {
+        while self.try_run_one() {}
+    }
+
+    /// Run the tasks for `dur`, waiting for new ones when none is queued.
+    ///
+    /// It doesn't return earlier once every [`Spawner`] and task is dropped, the executor keeps a
+    /// sender of its own for [`Executor::run_until`].
+    pub fn run_for(&self, dur: Duration) {
+        let deadline = Instant::now() + dur;
+        loop {
+            // `recv_timeout` returns a queued task even with a zero timeout, so a task waking
+            // itself would keep it running forever
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            match self.receiver.recv_timeout(deadline - now) {
+                Ok(task) => self.run_task(task),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Run the tasks until `future` completes, returns its output.
+    ///
+    /// The future is polled on the current thread, so it doesn't need to be `Send`.
+    pub fn run_until<F: Future>(&self, future: F) -> F::Output {
+        let Some(sync_sender) = self.sync_sender.lock().unwrap().clone() else {
+            // `run` has returned, no task is left to run
+            return futures::executor::block_on(future);
+        };
+        // a task without a future, it is queued like the others whenever `future` is woken
+        let main = Arc::new(Task::new(Mutex::new(None), sync_sender));
+        let waker = waker_ref(&main);
+        let cx = &mut Context::from_waker(&waker);
+        let mut future = pin!(future);
+
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                return output;
+            }
+            loop {
+                let task = self.receiver.recv().expect("`main` holds a sender");
+                if Arc::ptr_eq(&task, &main) {
+                    break;
                 }
+                self.run_task(task);
+            }
+        }
+    }
+
+    fn run_task(&self, task: Arc<Task>) {
+        let mut future_guard = task.future.lock().unwrap();
+        if let Some(mut future) = future_guard.take() {
+            if task.aborted.load(Ordering::Acquire) {
+                drop(future);
+                task.finished.store(true, Ordering::Release);
+                return;
+            }
+            let waker = waker_ref(&task);
+            let cx = &mut Context::from_waker(&waker);
+            if future.as_mut().poll(cx).is_pending() {
+                *future_guard = Some(future);
+            } else {
+                // report the completion to the `JoinHandle`
+                task.finished.store(true, Ordering::Release);
             }
         }
     }
@@ -44,5 +195,9 @@ impl Executor {
 pub fn new_exector_and_spawner() -> (Executor, Spawner) {
     const MAX_QUEUED_TASKS: usize = 10_000;
     let (sync_sender, receiver) = sync_channel(MAX_QUEUED_TASKS);
-    (Executor { receiver }, Spawner { sync_sender })
+    let executor = Executor {
+        receiver,
+        sync_sender: Mutex::new(Some(sync_sender.clone())),
+    };
+    (executor, Spawner { sync_sender })
 }
@@ -1,42 +1,141 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::pin::Pin;
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
 use std::sync::mpsc::SyncSender;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
-use futures::future::BoxFuture;
+use futures::future::{poll_fn, BoxFuture};
 use futures::task::ArcWake;
-use futures::Future;
+use futures::{ready, Future, Stream};
+use once_cell::sync::Lazy;
+
+/// The timer thread shared by all [`TimerFuture`]s, started on first use.
+static TIMER: Lazy<Timer> = Lazy::new(|| {
+    let timers: Arc<Mutex<Timers>> = Default::default();
+    let thread = thread::Builder::new()
+        .name("timer".into())
+        .spawn({
+            let timers = timers.clone();
+            move || run_timer(&timers)
+        })
+        .expect("failed to start the timer thread")
+        .thread()
+        .clone();
+    Timer { timers, thread }
+});
+
+/// The pending timers ordered by deadline, the id tells apart the ones with the same deadline.
+type Timers = BTreeMap<(Instant, u64), Arc<Mutex<Inner>>>;
+
+struct Timer {
+    timers: Arc<Mutex<Timers>>,
+    thread: Thread,
+}
+
+impl Timer {
+    fn insert(&self, timers: &mut Timers, key: (Instant, u64), inner: Arc<Mutex<Inner>>) {
+        timers.insert(key, inner);
+        // the thread sleeps until the earliest deadline, which is the new one now
+        if timers.first_key_value().is_some_and(|(k, _)| *k == key) {
+            self.thread.unpark();
+        }
+    }
+}
+
+/// Fire the expired timers, then park until the next deadline or a new earlier timer.
+fn run_timer(timers: &Mutex<Timers>) {
+    let mut wakers = vec![];
+    loop {
+        let now = Instant::now();
+        let mut guard = timers.lock().unwrap();
+        while let Some(entry) = guard.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let mut inner = entry.get().lock().unwrap();
+            inner.completed = true;
+            wakers.extend(inner.waker.take());
+            drop(inner);
+            entry.remove();
+        }
+        let next = guard.first_key_value().map(|(k, _)| k.0);
+        drop(guard);
+
+        // wake outside the lock, a woken task may register another timer at once, and a panic
+        // of a waker must not stop the timers of everybody else
+        for waker in wakers.drain(..) {
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| waker.wake()));
+        }
+        match next {
+            Some(deadline) => thread::park_timeout(deadline.saturating_duration_since(now)),
+            None => thread::park(),
+        }
+    }
+}
 
 struct Inner {
     completed: bool,
     waker: Option<Waker>,
 }
 
+/// A future completing at a deadline, all timers are driven by a single shared thread.
+///
+/// Dropping the future cancels the timer.
 pub struct TimerFuture {
     inner: Arc<Mutex<Inner>>,
+    deadline: Instant,
+    id: u64,
 }
 
 impl TimerFuture {
     pub fn new(dur: Duration) -> Self {
+        Self::at(Instant::now() + dur)
+    }
+
+    /// Create a timer completing at `deadline`, at once if it has passed.
+    pub fn at(deadline: Instant) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
         let inner = Arc::new(Mutex::new(Inner {
             completed: false,
             waker: None,
         }));
-        let weak_inner_rc = Arc::downgrade(&inner);
-        thread::spawn(move || {
-            thread::sleep(dur);
-            let inner = weak_inner_rc.upgrade().unwrap();
-            let mut inner_guard = inner.lock().unwrap();
-            inner_guard.completed = true;
-            // After `Future` has been polled once, it is set `waker`
-            if let Some(waker) = inner_guard.waker.take() {
-                waker.wake()
-            }
-        });
+        let id = NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed);
+        let mut timers = TIMER.timers.lock().unwrap();
+        TIMER.insert(&mut timers, (deadline, id), inner.clone());
+        drop(timers);
+
+        Self {
+            inner,
+            deadline,
+            id,
+        }
+    }
 
-        Self { inner }
+    /// Re-arm the timer to complete at `deadline`, whether it has completed or not.
+    ///
+    /// The task waiting on the timer isn't woken, it will be at the new deadline.
+    pub fn reset(&mut self, deadline: Instant) {
+        // under the lock of the timers, so the timer thread can't fire the old entry meanwhile
+        let mut timers = TIMER.timers.lock().unwrap();
+        timers.remove(&(self.deadline, self.id));
+        self.inner.lock().unwrap().completed = false;
+        self.deadline = deadline;
+        TIMER.insert(&mut timers, (deadline, self.id), self.inner.clone());
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// whether the deadline has passed, the timer thread may not have fired it yet.
+    pub fn is_elapsed(&self) -> bool {
+        self.inner.lock().unwrap().completed || Instant::now() >= self.deadline
     }
 }
 
@@ -45,7 +144,8 @@ impl Future for TimerFuture {
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut shared_data = self.inner.lock().unwrap();
-        if shared_data.completed {
+        // the timer thread may lag behind, the deadline is checked here as well
+        if shared_data.completed || Instant::now() >= self.deadline {
             Poll::Ready(())
         } else {
             shared_data.waker = Some(cx.waker().clone());
@@ -54,6 +154,201 @@ impl Future for TimerFuture {
     }
 }
 
+impl Drop for TimerFuture {
+    fn drop(&mut self) {
+        // nothing to remove if it has fired already
+        TIMER
+            .timers
+            .lock()
+            .unwrap()
+            .remove(&(self.deadline, self.id));
+    }
+}
+
+/// Wait until `dur` has elapsed.
+pub fn sleep(dur: Duration) -> TimerFuture {
+    TimerFuture::new(dur)
+}
+
+/// Wait until `deadline`.
+pub fn sleep_until(deadline: Instant) -> TimerFuture {
+    TimerFuture::at(deadline)
+}
+
+/// Create an [`Interval`] ticking every `period`, the first tick completes at once.
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "`period` must be non-zero");
+    Interval {
+        timer: TimerFuture::at(Instant::now()),
+        period,
+    }
+}
+
+/// Ticks at a fixed period, see [`interval`].
+///
+/// The ticks missed because the task was busy are skipped, the next tick is kept on the
+/// original schedule.
+pub struct Interval {
+    timer: TimerFuture,
+    period: Duration,
+}
+
+impl Interval {
+    /// Wait for the next tick, returns its scheduled instant.
+    pub async fn tick(&mut self) -> Instant {
+        poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        ready!(Pin::new(&mut self.timer).poll(cx));
+
+        let tick = self.timer.deadline;
+        let now = Instant::now();
+        let mut next = tick + self.period;
+        if next <= now {
+            let behind = (now - tick).as_nanos() % self.period.as_nanos();
+            next = now + self.period - Duration::from_nanos(behind as u64);
+        }
+        self.timer.reset(next);
+        Poll::Ready(tick)
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
+
+/// Wait for `future` for at most `dur`, see [`timeout_at`].
+pub fn timeout<F: Future>(dur: Duration, future: F) -> Timeout<F> {
+    timeout_at(Instant::now() + dur, future)
+}
+
+/// Wait for `future` until `deadline`, it is dropped with the returned future if the deadline
+/// passes first.
+pub fn timeout_at<F: Future>(deadline: Instant, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        timer: TimerFuture::at(deadline),
+    }
+}
+
+/// The future of [`timeout`] and [`timeout_at`].
+pub struct Timeout<F> {
+    future: F,
+    timer: TimerFuture,
+}
+
+impl<F> Timeout<F> {
+    pub fn get_ref(&self) -> &F {
+        &self.future
+    }
+
+    pub fn into_inner(self) -> F {
+        self.future
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `future` is pinned along with `self` and never moved out, `timer` is `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        // the future wins if both are ready
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.timer)
+            .poll(cx)
+            .map(|()| Err(Elapsed(())))
+    }
+}
+
+/// Wrap `stream` so waiting for each item takes at most `dur`, see [`TimeoutStream`].
+pub fn timeout_stream<S: Stream>(dur: Duration, stream: S) -> TimeoutStream<S> {
+    TimeoutStream {
+        stream,
+        dur,
+        timer: None,
+        armed: false,
+    }
+}
+
+/// Yields `Err(Elapsed)` each time the next item takes longer than the duration, then keeps
+/// waiting for it with a new deadline.
+///
+/// The deadline of an item starts when the stream is first polled for it.
+pub struct TimeoutStream<S> {
+    stream: S,
+    dur: Duration,
+    // reused across the items, it is `None` until the first deadline
+    timer: Option<TimerFuture>,
+    armed: bool,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream> Stream for TimeoutStream<S> {
+    type Item = Result<S::Item, Elapsed>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // SAFETY: `stream` is pinned along with `self` and never moved out, the rest is `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+
+        if let Poll::Ready(item) = stream.poll_next(cx) {
+            this.armed = false;
+            return Poll::Ready(item.map(Ok));
+        }
+
+        if !this.armed {
+            let deadline = Instant::now() + this.dur;
+            match &mut this.timer {
+                Some(timer) => timer.reset(deadline),
+                None => this.timer = Some(TimerFuture::at(deadline)),
+            }
+            this.armed = true;
+        }
+        ready!(Pin::new(this.timer.as_mut().unwrap()).poll(cx));
+        this.armed = false;
+        Poll::Ready(Some(Err(Elapsed(()))))
+    }
+}
+
+/// The deadline of [`timeout`] or [`TimeoutStream`] has passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
 pub struct Task {
     // each `Waker` hold a reference to the corresponding task, so the task will shared in
     // different threads, and the `poll` method need a mutable future, so we need use `Mutex` to
@@ -61,6 +356,10 @@ pub struct Task {
     pub future: Mutex<Option<BoxFuture<'static, ()>>>,
     // send task to executor
     pub sync_sender: SyncSender<Arc<Task>>,
+    // set by `JoinHandle::abort`, the executor drops the future instead of polling it
+    pub aborted: AtomicBool,
+    // set by the executor once the future is completed or dropped
+    pub finished: AtomicBool,
 }
 
 impl Task {
@@ -71,6 +370,8 @@ impl Task {
         Self {
             future: futrue,
             sync_sender,
+            aborted: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
         }
     }
 }
@@ -78,9 +379,7 @@ impl Task {
 impl ArcWake for Task {
     fn wake_by_ref(arc_self: &Arc<Self>) {
         let cloned = arc_self.clone();
-        arc_self
-            .sync_sender
-            .send(cloned)
-            .expect("too many tasks queued");
+        // the executor may be gone, then nobody would poll the task anyway
+        let _ = arc_self.sync_sender.send(cloned);
     }
 }
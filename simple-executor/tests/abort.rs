@@ -0,0 +1,22 @@
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use simple_executor::best_executor::{block_on, ThreadPool};
+
+#[test]
+fn abort_before_first_run() {
+    static PANICS: AtomicUsize = AtomicUsize::new(0);
+    panic::set_hook(Box::new(|_| {
+        PANICS.fetch_add(1, Ordering::SeqCst);
+    }));
+
+    let pool = ThreadPool::builder().threads(4).build().unwrap();
+    for _ in 0..100_000 {
+        pool.spawn(async {}).abort();
+    }
+    let handles: Vec<_> = (0..64).map(|i| pool.spawn(async move { i })).collect();
+    let sum: usize = handles.into_iter().map(|h| block_on(h).unwrap()).sum();
+
+    assert_eq!(sum, (0..64).sum());
+    assert_eq!(PANICS.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use simple_executor::best_executor::ThreadPool;
+
+struct Out<'a>(&'a AtomicBool);
+
+impl Drop for Out<'_> {
+    fn drop(&mut self) {
+        // give `scope` the chance to return early if it doesn't wait for the output
+        thread::sleep(Duration::from_millis(20));
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn scope_waits_for_unclaimed_output() {
+    let pool = ThreadPool::builder().threads(1).build().unwrap();
+    for _ in 0..3 {
+        let dropped = AtomicBool::new(false);
+        pool.scope(|s| {
+            drop(s.spawn(async {
+                thread::sleep(Duration::from_millis(20));
+                Out(&dropped)
+            }));
+        });
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,51 @@
+//! Compare the syscalls of the reactor modes on a TCP ping-pong, run with
+//! `cargo run --release --example reactor_modes [rounds]`.
+
+use std::time::Instant;
+
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use simple_runtime::executor::{Executor, ReactorMode};
+use simple_runtime::tcp::{TcpListener, TcpStream};
+
+fn ping_pong(mode: ReactorMode, rounds: usize) {
+    let ex = Executor::with_reactor_mode(mode);
+    let start = Instant::now();
+
+    ex.block_on(|| async move {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        Executor::spawn(async move {
+            let (mut stream, _) = listener.next().await.unwrap().unwrap();
+            let mut buf = [0; 4];
+            for _ in 0..rounds {
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            }
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 4];
+        for _ in 0..rounds {
+            stream.write_all(b"ping").await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+        }
+    });
+
+    let stats = ex.reactor_stats();
+    println!(
+        "{mode:?}: {} epoll_ctl, {} epoll_wait, {:?}",
+        stats.ctl_calls,
+        stats.wait_calls,
+        start.elapsed()
+    );
+}
+
+fn main() {
+    let rounds = std::env::args()
+        .nth(1)
+        .map_or(20_000, |n| n.parse().expect("rounds must be a number"));
+
+    ping_pong(ReactorMode::Oneshot, rounds);
+    ping_pong(ReactorMode::EdgeTriggered, rounds);
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+
+use futures::future::poll_fn;
+
+use crate::reactor::{get_reactor, Reactor};
+
+pub use crate::reactor::Readiness;
+
+/// Register an arbitrary fd, e.g. eventfd, timerfd, inotify or a tun device, with the reactor of
+/// the current runtime.
+///
+/// The fd is set to nonblocking mode. `AsyncFd` doesn't do any IO itself, await
+/// [`AsyncFd::readable`] or [`AsyncFd::writable`] and do the IO with [`AsyncFdReadyGuard::try_io`],
+/// which tells whether the fd would block and the readiness must be waited again.
+pub struct AsyncFd<T: AsRawFd> {
+    inner: Option<T>,
+    fd: RawFd,
+    reactor: Weak<Reactor>,
+    // the interest of each direction has been registered, and its event may have come since
+    read_armed: AtomicBool,
+    write_armed: AtomicBool,
+}
+
+impl<T: AsRawFd> AsyncFd<T> {
+    /// # Errors
+    ///
+    /// Returns an error if the fd can't be registered with epoll, e.g. it is a regular file.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it is called outside a runtime.
+    pub fn new(inner: T) -> io::Result<Self> {
+        let fd = inner.as_raw_fd();
+        let reactor = get_reactor();
+        reactor.try_add(fd)?;
+
+        Ok(Self {
+            inner: Some(inner),
+            fd,
+            reactor: Arc::downgrade(&reactor),
+            read_armed: AtomicBool::new(false),
+            write_armed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.inner.as_ref().unwrap()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// deregister the fd from the reactor and return the inner object, the fd stays nonblocking.
+    pub fn into_inner(mut self) -> T {
+        self.deregister();
+        self.inner.take().unwrap()
+    }
+
+    /// Waits for the fd to become readable.
+    pub async fn readable(&self) -> io::Result<AsyncFdReadyGuard<'_, T>> {
+        poll_fn(|cx| self.poll_read_ready(cx)).await
+    }
+
+    /// Waits for the fd to become writable.
+    pub async fn writable(&self) -> io::Result<AsyncFdReadyGuard<'_, T>> {
+        poll_fn(|cx| self.poll_write_ready(cx)).await
+    }
+
+    pub fn poll_read_ready(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<AsyncFdReadyGuard<'_, T>>> {
+        self.poll_ready(cx, false)
+    }
+
+    pub fn poll_write_ready(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<AsyncFdReadyGuard<'_, T>>> {
+        self.poll_ready(cx, true)
+    }
+
+    /// Waits for the peer to close or an error on the fd, without reading any data.
+    pub async fn closed(&self) -> io::Result<Readiness> {
+        poll_fn(|cx| self.poll_closed(cx)).await
+    }
+
+    pub fn poll_closed(&self, cx: &mut Context<'_>) -> Poll<io::Result<Readiness>> {
+        match self.reactor.upgrade() {
+            Some(reactor) => reactor.poll_closed(self.fd, cx),
+            None => Poll::Ready(Err(io::Error::other("the reactor is gone"))),
+        }
+    }
+
+    /// Returns the current readiness of the fd, including hangup and errors, without waiting.
+    pub fn readiness(&self) -> io::Result<Readiness> {
+        Readiness::of(self.fd)
+    }
+
+    /// Reads with `f` until it doesn't return `WouldBlock`, waiting for readability in between.
+    pub async fn read_with<R>(&self, mut f: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            let mut guard = self.readable().await?;
+            if let Ok(ret) = guard.try_io(|fd| f(fd.get_ref())) {
+                return ret;
+            }
+        }
+    }
+
+    /// Writes with `f` until it doesn't return `WouldBlock`, waiting for writability in between.
+    pub async fn write_with<R>(&self, mut f: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            let mut guard = self.writable().await?;
+            if let Ok(ret) = guard.try_io(|fd| f(fd.get_ref())) {
+                return ret;
+            }
+        }
+    }
+
+    /// The reactor removes the waker when the event is triggered, so if the interest is armed and
+    /// the waker is gone, the fd is ready. Otherwise (re-)register the interest with the current
+    /// waker.
+    fn poll_ready(
+        &self,
+        cx: &mut Context<'_>,
+        writable: bool,
+    ) -> Poll<io::Result<AsyncFdReadyGuard<'_, T>>> {
+        let Some(reactor) = self.reactor.upgrade() else {
+            return Poll::Ready(Err(io::Error::other("the reactor is gone")));
+        };
+
+        let (armed, pending) = if writable {
+            (&self.write_armed, reactor.is_pending_writable(self.fd))
+        } else {
+            (&self.read_armed, reactor.is_pending_readable(self.fd))
+        };
+
+        if armed.load(Ordering::Acquire) && !pending {
+            armed.store(false, Ordering::Release);
+            return Poll::Ready(Ok(AsyncFdReadyGuard {
+                async_fd: self,
+                writable,
+            }));
+        }
+
+        if writable {
+            reactor.interest_writable(self.fd, cx);
+        } else {
+            reactor.interest_readable(self.fd, cx);
+        }
+        armed.store(true, Ordering::Release);
+        Poll::Pending
+    }
+
+    fn deregister(&self) {
+        if let Some(reactor) = self.reactor.upgrade() {
+            let _ = reactor.remove(self.fd);
+        }
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for AsyncFd<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl<T: AsRawFd + fmt::Debug> fmt::Debug for AsyncFd<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncFd")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<T: AsRawFd> Drop for AsyncFd<T> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            self.deregister();
+        }
+    }
+}
+
+/// The fd of the [`AsyncFd`] may be ready for the direction it was awaited for.
+pub struct AsyncFdReadyGuard<'a, T: AsRawFd> {
+    async_fd: &'a AsyncFd<T>,
+    writable: bool,
+}
+
+/// The IO in [`AsyncFdReadyGuard::try_io`] would block.
+#[derive(Debug)]
+pub struct TryIoError(());
+
+impl<'a, T: AsRawFd> AsyncFdReadyGuard<'a, T> {
+    pub fn get_ref(&self) -> &'a AsyncFd<T> {
+        self.async_fd
+    }
+
+    pub fn get_inner(&self) -> &'a T {
+        self.async_fd.get_ref()
+    }
+
+    /// whether the guard is for writability, otherwise readability.
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// the current readiness of the fd, e.g. to tell a hangup from data after a readable event.
+    pub fn readiness(&self) -> io::Result<Readiness> {
+        self.async_fd.readiness()
+    }
+
+    /// Run the IO operation `f`, returns `Err(TryIoError)` if it fails with `WouldBlock`, then the
+    /// readiness must be awaited again.
+    pub fn try_io<R>(
+        &mut self,
+        f: impl FnOnce(&'a AsyncFd<T>) -> io::Result<R>,
+    ) -> Result<io::Result<R>, TryIoError> {
+        match f(self.async_fd) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Err(TryIoError(())),
+            ret => Ok(ret),
+        }
+    }
+}
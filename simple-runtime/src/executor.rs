@@ -1,26 +1,161 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::mem::ManuallyDrop;
 use std::pin::Pin;
+use std::ptr::NonNull;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, RawWaker, Waker};
 
-use futures::future::LocalBoxFuture;
-use futures::{Future, FutureExt};
 use scoped_tls::scoped_thread_local;
 use waker_fn::waker_fn;
 
 use crate::helper::Helper;
-use crate::reactor::Reactor;
+use crate::reactor::{Notifier, Reactor};
+
+pub use crate::reactor::{ReactorMode, ReactorStats};
 
 scoped_thread_local!(pub(crate) static EX: Executor);
 
-pub struct Task {
-    future: RefCell<LocalBoxFuture<'static, ()>>,
+/// The type-erased part at the start of every task.
+struct Header {
+    // the task is in the queue, waking it again doesn't queue it twice
+    scheduled: Cell<bool>,
+    vtable: &'static TaskVTable,
+}
+
+/// The functions of a task that depend on the type of its future.
+struct TaskVTable {
+    /// returns true if the future completed in this poll
+    poll: unsafe fn(NonNull<Header>, &mut Context<'_>) -> bool,
+    clone: unsafe fn(NonNull<Header>),
+    drop: unsafe fn(NonNull<Header>),
+}
+
+/// A task is a single `Rc` allocation, the header followed by the future inline.
+#[repr(C)]
+struct Task<F> {
+    header: Header,
+    // `None` once completed, a task may still be woken and queued after that
+    future: RefCell<Option<F>>,
+}
+
+impl<F: Future<Output = ()> + 'static> Task<F> {
+    const VTABLE: TaskVTable = TaskVTable {
+        poll: Self::poll,
+        clone: Self::clone,
+        drop: Self::drop,
+    };
+
+    unsafe fn poll(ptr: NonNull<Header>, cx: &mut Context<'_>) -> bool {
+        let task = ptr.cast::<Self>().as_ref();
+        let mut future = task.future.borrow_mut();
+        let Some(fut) = future.as_mut() else {
+            return false;
+        };
+        // SAFETY: the future lives inline in the `Rc` allocation, which never moves.
+        if Pin::new_unchecked(fut).poll(cx).is_ready() {
+            *future = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    unsafe fn clone(ptr: NonNull<Header>) {
+        Rc::increment_strong_count(ptr.cast::<Self>().as_ptr());
+    }
+
+    unsafe fn drop(ptr: NonNull<Header>) {
+        Rc::decrement_strong_count(ptr.cast::<Self>().as_ptr());
+    }
+}
+
+impl<F> Drop for Task<F> {
+    /// a task dropped before completion, e.g. when nothing can wake it any more, is no longer live.
+    fn drop(&mut self) {
+        if self.future.get_mut().is_some() && EX.is_set() {
+            EX.with(|ex| ex.live.set(ex.live.get() - 1));
+        }
+    }
+}
+
+/// An owned reference to a task, like an `Rc` with the type of the future erased.
+pub(crate) struct TaskRef(NonNull<Header>);
+
+impl TaskRef {
+    fn new<F: Future<Output = ()> + 'static>(future: F) -> Self {
+        let task = Rc::new(Task {
+            header: Header {
+                scheduled: Cell::new(false),
+                vtable: &Task::<F>::VTABLE,
+            },
+            future: RefCell::new(Some(future)),
+        });
+        // SAFETY: `Task` is `repr(C)`, so the header is at the start of the allocation.
+        Self(unsafe { NonNull::new_unchecked(Rc::into_raw(task) as *mut Header) })
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be the data of a task waker, the reference it owns is taken over.
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self(NonNull::new_unchecked(ptr as *mut Header))
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: the task is alive as long as a reference is.
+        unsafe { self.0.as_ref() }
+    }
+
+    /// Queue the task on the current executor unless it is queued already.
+    pub(crate) fn schedule(self) {
+        if !self.header().scheduled.replace(true) {
+            EX.with(|ex| ex.local_queue.push(self));
+        }
+    }
+
+    pub(crate) fn wake_by_ref(&self) {
+        if !self.header().scheduled.replace(true) {
+            EX.with(|ex| ex.local_queue.push(self.clone()));
+        }
+    }
+
+    /// Poll the task, returns true if it completed in this poll.
+    ///
+    /// The waker borrows this reference instead of owning one, building it costs nothing, only the
+    /// clones kept by the future touch the reference count.
+    fn poll(&self) -> bool {
+        self.header().scheduled.set(false);
+        let raw = RawWaker::new(self.0.as_ptr() as *const (), &Helper::VTABLE);
+        // SAFETY: the waker is never dropped, so it never releases the borrowed reference.
+        let waker = ManuallyDrop::new(unsafe { Waker::from_raw(raw) });
+        let cx = &mut Context::from_waker(&waker);
+        // SAFETY: the vtable matches the type of the task.
+        unsafe { (self.header().vtable.poll)(self.0, cx) }
+    }
+}
+
+impl Clone for TaskRef {
+    fn clone(&self) -> Self {
+        // SAFETY: the vtable matches the type of the task.
+        unsafe { (self.header().vtable.clone)(self.0) };
+        Self(self.0)
+    }
+}
+
+impl Drop for TaskRef {
+    fn drop(&mut self) {
+        // SAFETY: the vtable matches the type of the task, and the header is not used afterwards.
+        unsafe { (self.header().vtable.drop)(self.0) }
+    }
 }
 
 #[derive(Default)]
 pub struct TaskQueue {
-    queue: RefCell<VecDeque<Rc<Task>>>,
+    queue: RefCell<VecDeque<TaskRef>>,
 }
 
 impl TaskQueue {
@@ -33,7 +168,7 @@ impl TaskQueue {
     /// # Returns
     ///
     /// Nothing.
-    pub(crate) fn push(&self, runnable: Rc<Task>) {
+    pub(crate) fn push(&self, runnable: TaskRef) {
         self.queue.borrow_mut().push_back(runnable);
     }
 
@@ -45,34 +180,54 @@ impl TaskQueue {
     ///
     /// # Returns
     ///
-    /// * `Some(Rc<Task>)` - The task that was popped off the queue.
+    /// * `Some(TaskRef)` - The task that was popped off the queue.
     /// * `None` - If the queue is empty.
-    pub(crate) fn pop(&self) -> Option<Rc<Task>> {
+    pub(crate) fn pop(&self) -> Option<TaskRef> {
         self.queue.borrow_mut().pop_front()
     }
+
+    fn is_empty(&self) -> bool {
+        self.queue.borrow().is_empty()
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A queue other threads push jobs into, the jobs are run on the executor's thread inside
+/// `block_on`, so they can spawn `!Send` futures with [`Executor::spawn`].
+pub(crate) struct Injector {
+    jobs: Mutex<VecDeque<Job>>,
+    notifier: Notifier,
 }
 
-impl Task {
-    /// Wakes up the task by calling `wake_by_ref_` on the `Rc<Self>`
-    pub fn wake_(self: Rc<Self>) {
-        Self::wake_by_ref_(&self);
+impl Injector {
+    /// push a job and wake up the reactor in case the executor is blocking for IO.
+    pub(crate) fn push(&self, job: Job) {
+        self.jobs.lock().unwrap().push_back(job);
+        self.notifier.notify();
     }
 
-    /// Wakes up the task by calling `wake_by_ref_` on the `Rc<Self>`
-    pub fn wake_by_ref_(self: &Rc<Self>) {
-        EX.with(|ex| ex.local_queue.push(self.clone()))
+    /// wake up the reactor without pushing a job.
+    pub(crate) fn notify(&self) {
+        self.notifier.notify();
+    }
+
+    /// take all pending jobs, the lock is released before running them.
+    fn take(&self) -> VecDeque<Job> {
+        std::mem::take(&mut *self.jobs.lock().unwrap())
     }
-}
 
-fn waker(wake: Rc<Task>) -> Waker {
-    let ptr = Rc::into_raw(wake) as *const ();
-    let vtable = &Helper::VTABLE;
-    unsafe { Waker::from_raw(RawWaker::new(ptr, vtable)) }
+    fn is_empty(&self) -> bool {
+        self.jobs.lock().unwrap().is_empty()
+    }
 }
 
 pub struct Executor {
     local_queue: TaskQueue,
-    pub(crate) reactor: Rc<RefCell<Reactor>>,
+    // the number of spawned tasks not completed yet
+    live: Cell<usize>,
+    pub(crate) reactor: Arc<Reactor>,
+    pub(crate) injector: Arc<Injector>,
 }
 
 impl Default for Executor {
@@ -83,21 +238,46 @@ impl Default for Executor {
 
 impl Executor {
     pub fn new() -> Self {
+        Self::with_reactor_mode(ReactorMode::default())
+    }
+
+    /// create an executor whose reactor registers fds in `mode`.
+    pub fn with_reactor_mode(mode: ReactorMode) -> Self {
+        let reactor = Reactor::new(mode);
+        let injector = Arc::new(Injector {
+            jobs: Default::default(),
+            notifier: reactor.notifier(),
+        });
         Self {
             local_queue: Default::default(),
-            reactor: Rc::new(RefCell::new(Reactor::default())),
+            live: Cell::new(0),
+            reactor: Arc::new(reactor),
+            injector,
+        }
+    }
+
+    /// Returns the syscalls made by the reactor of this executor so far.
+    pub fn reactor_stats(&self) -> ReactorStats {
+        self.reactor.stats()
+    }
+
+    /// Returns a handle to spawn onto this executor from anywhere, see [`Handle`].
+    pub fn handle(&self) -> Handle {
+        Handle {
+            injector: ManuallyDrop::new(self.injector.clone()),
         }
     }
 
+    /// # Panics
+    ///
+    /// This function will panic if it is called outside `block_on`, use a [`Handle`] there.
     pub fn spawn<F>(fut: F)
     where
         F: Future<Output = ()> + 'static,
     {
-        let t = Rc::new(Task {
-            future: RefCell::new(fut.boxed_local()),
-        });
-
-        EX.with(|ex| ex.local_queue.push(t));
+        let t = TaskRef::new(fut);
+        EX.with(|ex| ex.live.set(ex.live.get() + 1));
+        t.schedule();
     }
 
     pub fn block_on<F, T, O>(&self, f: F) -> O
@@ -117,13 +297,7 @@ impl Executor {
                     break t;
                 }
 
-                // consum all tasks
-                while let Some(t) = self.local_queue.pop() {
-                    let future = t.future.borrow_mut();
-                    let w = waker(t.clone());
-                    let ctx = &mut Context::from_waker(&w);
-                    let _ = Pin::new(future).as_mut().poll(ctx);
-                }
+                self.run_queued();
 
                 // no task to execute now, outer future may ready
                 if let Poll::Ready(t) = fut.as_mut().poll(cx) {
@@ -131,8 +305,173 @@ impl Executor {
                 }
 
                 // block for IO
-                self.reactor.borrow_mut().wait();
+                self.reactor.wait();
             }
         })
     }
+
+    /// Like [`Executor::block_on`], but returns only when the root future and every spawned task
+    /// have completed, including the tasks they spawn.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DeadlockError`] if nothing can make progress any more: no task is queued, no
+    /// task waits for IO, and no [`Handle`] is left to spawn more, while the root future or some
+    /// tasks are still pending. Without the check the thread would block forever.
+    pub fn block_on_all<F, T, O>(&self, f: F) -> Result<O, DeadlockError>
+    where
+        F: Fn() -> T,
+        T: Future<Output = O> + 'static,
+    {
+        self.run_all(f, false)
+    }
+
+    /// Run the tasks spawned through [`Handle`]s until all of them complete and every handle is
+    /// dropped, see [`Executor::block_on_all`].
+    pub fn run(&self) -> Result<(), DeadlockError> {
+        self.run_all(|| async {}, true)
+    }
+
+    /// `wait_handles` keeps running while a [`Handle`] may still spawn tasks.
+    fn run_all<F, T, O>(&self, f: F, wait_handles: bool) -> Result<O, DeadlockError>
+    where
+        F: Fn() -> T,
+        T: Future<Output = O> + 'static,
+    {
+        let _waker = waker_fn(|| {});
+        let cx = &mut Context::from_waker(&_waker);
+
+        EX.set(self, || {
+            let fut = &mut f();
+            let mut fut = unsafe { Pin::new_unchecked(fut) };
+            let mut output = None;
+            loop {
+                if output.is_none() {
+                    if let Poll::Ready(t) = fut.as_mut().poll(cx) {
+                        output = Some(t);
+                    }
+                }
+
+                self.run_queued();
+
+                if output.is_none() {
+                    if let Poll::Ready(t) = fut.as_mut().poll(cx) {
+                        output = Some(t);
+                    }
+                }
+
+                // the executor's own reference is the only one, no handle can spawn any more
+                let handles = Arc::strong_count(&self.injector) > 1;
+                if self.live.get() == 0 && self.injector.is_empty() && !(wait_handles && handles) {
+                    if let Some(t) = output.take() {
+                        break Ok(t);
+                    }
+                }
+
+                // polling the root may wake tasks
+                if !self.local_queue.is_empty() || !self.injector.is_empty() {
+                    continue;
+                }
+                if !self.reactor.has_waiters() && !handles {
+                    break Err(DeadlockError {
+                        tasks: self.live.get(),
+                        root_pending: output.is_none(),
+                    });
+                }
+
+                // block for IO
+                self.reactor.wait();
+            }
+        })
+    }
+
+    /// run jobs sent from other threads, they may spawn new tasks, then consume all tasks.
+    fn run_queued(&self) {
+        for job in self.injector.take() {
+            job();
+        }
+
+        while let Some(t) = self.local_queue.pop() {
+            if t.poll() {
+                self.live.set(self.live.get() - 1);
+            }
+        }
+    }
+}
+
+/// Returned by [`Executor::block_on_all`] when pending tasks can never be woken.
+#[derive(Debug)]
+pub struct DeadlockError {
+    tasks: usize,
+    root_pending: bool,
+}
+
+impl DeadlockError {
+    /// the number of spawned tasks that never completed
+    pub fn tasks(&self) -> usize {
+        self.tasks
+    }
+
+    /// whether the root future never completed
+    pub fn root_pending(&self) -> bool {
+        self.root_pending
+    }
+}
+
+impl fmt::Display for DeadlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the executor is deadlocked with {} pending tasks",
+            self.tasks
+        )?;
+        if self.root_pending {
+            write!(f, " and the root future pending")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DeadlockError {}
+
+/// A cloneable, `Send` handle to spawn tasks onto an [`Executor`].
+///
+/// Unlike [`Executor::spawn`], it works before `block_on` starts, in sync code with no executor
+/// around, and from other threads. The tasks are queued and spawned by the executor the next
+/// time `block_on` polls, they are dropped if it never does.
+#[derive(Clone)]
+pub struct Handle {
+    // taken in `drop`, so the executor is woken only after the reference is released
+    injector: ManuallyDrop<Arc<Injector>>,
+}
+
+impl Handle {
+    pub fn spawn<F>(&self, fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.injector.push(Box::new(move || Executor::spawn(fut)));
+    }
+
+    /// Run `f` on the executor's thread and spawn the future it returns.
+    ///
+    /// Only the closure crosses threads, so the future doesn't need to be `Send`.
+    pub fn spawn_with<F, T>(&self, f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        self.injector.push(Box::new(move || Executor::spawn(f())));
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        // SAFETY: the field is never used again.
+        let injector = unsafe { ManuallyDrop::take(&mut self.injector) };
+        let notifier = injector.notifier.clone();
+        drop(injector);
+        // `Executor::run` may be waiting for the last handle
+        notifier.notify();
+    }
 }
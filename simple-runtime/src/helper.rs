@@ -1,8 +1,7 @@
 use std::mem;
-use std::rc::Rc;
 use std::task::{RawWaker, RawWakerVTable};
 
-use crate::executor::Task;
+use crate::executor::TaskRef;
 
 pub struct Helper;
 
@@ -21,17 +20,16 @@ impl Helper {
     }
 
     unsafe fn wake(ptr: *const ()) {
-        let rc = Rc::from_raw(ptr as *const Task);
-        rc.wake_();
+        TaskRef::from_raw(ptr).schedule();
     }
 
     unsafe fn wake_by_ref(ptr: *const ()) {
-        let rc = mem::ManuallyDrop::new(Rc::from_raw(ptr as *const Task));
-        rc.wake_by_ref_()
+        let task = mem::ManuallyDrop::new(TaskRef::from_raw(ptr));
+        task.wake_by_ref()
     }
 
     unsafe fn drop_waker(ptr: *const ()) {
-        drop(Rc::from_raw(ptr as *const Task));
+        drop(TaskRef::from_raw(ptr));
     }
 }
 
@@ -49,6 +47,6 @@ impl Helper {
 ///
 /// This function will panic if the given pointer is null.
 unsafe fn increase_refcount(data: *const ()) {
-    let rc = mem::ManuallyDrop::new(Rc::from_raw(data as *const Task));
-    let _: mem::ManuallyDrop<_> = rc.clone();
+    let task = mem::ManuallyDrop::new(TaskRef::from_raw(data));
+    let _: mem::ManuallyDrop<_> = task.clone();
 }
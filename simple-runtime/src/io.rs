@@ -0,0 +1,369 @@
+use std::io::{self, IoSlice};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures::{ready, AsyncRead, AsyncWrite};
+use nix::errno::Errno;
+use nix::fcntl::FcntlArg::{F_GETFL, F_SETFL};
+use nix::fcntl::{fcntl, OFlag};
+use nix::libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use nix::sys::uio::writev;
+use nix::unistd::{self, isatty, pipe2};
+
+use crate::async_fd::AsyncFd;
+
+/// Read from a nonblocking fd registered with the reactor.
+pub(crate) fn poll_read_fd<T: AsRawFd>(
+    fd: &AsyncFd<T>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    loop {
+        match unistd::read(fd.as_raw_fd(), buf) {
+            Ok(n) => return Poll::Ready(Ok(n)),
+            Err(Errno::EAGAIN) => {
+                ready!(fd.poll_read_ready(cx))?;
+            }
+            Err(e) => return Poll::Ready(Err(e.into())),
+        }
+    }
+}
+
+/// Write to a nonblocking fd registered with the reactor.
+pub(crate) fn poll_write_fd<T: AsRawFd>(
+    fd: &AsyncFd<T>,
+    cx: &mut Context<'_>,
+    bufs: &[IoSlice<'_>],
+) -> Poll<io::Result<usize>> {
+    loop {
+        match writev(fd.as_raw_fd(), bufs) {
+            Ok(n) => return Poll::Ready(Ok(n)),
+            Err(Errno::EAGAIN) => {
+                ready!(fd.poll_write_ready(cx))?;
+            }
+            Err(e) => return Poll::Ready(Err(e.into())),
+        }
+    }
+}
+
+/// Create a pipe, both ends are registered with the reactor of the current runtime.
+pub fn pipe() -> io::Result<(PipeReader, PipeWriter)> {
+    let (r, w) = pipe2(OFlag::O_CLOEXEC)?;
+    // SAFETY: both fds are just created by `pipe2` and owned by nobody else.
+    let (r, w) = unsafe { (OwnedFd::from_raw_fd(r), OwnedFd::from_raw_fd(w)) };
+    Ok((PipeReader::new(r)?, PipeWriter::new(w)?))
+}
+
+/// The read end of a pipe.
+pub struct PipeReader {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl PipeReader {
+    /// register the read end of a pipe created elsewhere, e.g. the stdout of a child process.
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    pub fn into_inner(self) -> OwnedFd {
+        self.fd.into_inner()
+    }
+}
+
+impl AsRawFd for PipeReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsyncRead for PipeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        poll_read_fd(&self.fd, cx, buf)
+    }
+}
+
+/// The write end of a pipe, the pipe is closed when it is dropped.
+pub struct PipeWriter {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl PipeWriter {
+    /// register the write end of a pipe created elsewhere, e.g. the stdin of a child process.
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    pub fn into_inner(self) -> OwnedFd {
+        self.fd.into_inner()
+    }
+}
+
+impl AsRawFd for PipeWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        poll_write_fd(&self.fd, cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        poll_write_fd(&self.fd, cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // a pipe can't be half closed, the reader sees EOF once `self` is dropped
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A standard stream fd, it is never closed.
+struct StdFd(RawFd);
+
+impl AsRawFd for StdFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// The flags of the standard streams before any of them is made nonblocking.
+///
+/// The `O_NONBLOCK` flag lives in the open file description, which is shared with the parent
+/// process, and may be shared between the standard streams themselves, e.g. stdin and stdout
+/// on the same socket. So the flags of all of them are saved when
+/// the first [`Nonblocking`] is created, and restored once the last one is dropped, whatever
+/// the order they are dropped in.
+static STD_FLAGS: Mutex<StdFlags> = Mutex::new(StdFlags {
+    live: 0,
+    saved: [None; 3],
+    touched: [false; 3],
+});
+
+struct StdFlags {
+    live: usize,
+    saved: [Option<OFlag>; 3],
+    // the fds registered with the reactor since the flags were saved
+    touched: [bool; 3],
+}
+
+impl StdFlags {
+    fn restore(&mut self, fd: RawFd) {
+        if let Some(flags) = self.saved[fd as usize] {
+            let _ = fcntl(fd, F_SETFL(flags));
+        }
+        self.touched[fd as usize] = false;
+    }
+}
+
+/// A standard stream registered with the reactor, see [`STD_FLAGS`].
+struct Nonblocking {
+    fd: Option<AsyncFd<StdFd>>,
+}
+
+impl Nonblocking {
+    /// Returns `Ok(None)` if the fd can't be polled, e.g. it is a regular file or `/dev/null`,
+    /// or if it is a terminal.
+    ///
+    /// A terminal is shared with the shell, which would be left nonblocking if the process is
+    /// killed or exits without dropping the stream, so it is never switched.
+    fn new(fd: RawFd) -> io::Result<Option<Self>> {
+        if isatty(fd).unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let mut std_flags = STD_FLAGS.lock().unwrap();
+        if std_flags.live == 0 {
+            for std_fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+                std_flags.saved[std_fd as usize] =
+                    fcntl(std_fd, F_GETFL).ok().map(OFlag::from_bits_truncate);
+            }
+        }
+
+        match AsyncFd::new(StdFd(fd)) {
+            Ok(async_fd) => {
+                std_flags.live += 1;
+                std_flags.touched[fd as usize] = true;
+                Ok(Some(Self { fd: Some(async_fd) }))
+            }
+            Err(e) => {
+                // a fd that can't be polled never shares its description with one that can
+                if !std_flags.touched[fd as usize] {
+                    std_flags.restore(fd);
+                }
+                if e.raw_os_error() == Some(Errno::EPERM as i32) {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    fn fd(&self) -> &AsyncFd<StdFd> {
+        self.fd.as_ref().unwrap()
+    }
+}
+
+impl Drop for Nonblocking {
+    fn drop(&mut self) {
+        // deregister from the reactor before the flags are restored
+        self.fd.take().unwrap().into_inner();
+
+        let mut std_flags = STD_FLAGS.lock().unwrap();
+        std_flags.live -= 1;
+        if std_flags.live == 0 {
+            for fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+                if std_flags.touched[fd as usize] {
+                    std_flags.restore(fd);
+                }
+            }
+        }
+    }
+}
+
+enum StdinInner {
+    Nonblocking(Nonblocking),
+    // a helper thread does the blocking reads and forwards the data through a pipe
+    Offload(PipeReader),
+}
+
+/// The standard input, see [`stdin`].
+pub struct Stdin {
+    inner: StdinInner,
+}
+
+/// Returns the standard input of the current process.
+///
+/// Pipes and sockets are read in nonblocking mode. Terminals and the fds that can't be polled,
+/// like regular files, are read on a helper thread, so the reactor thread is never blocked.
+///
+/// The nonblocking mode of a pipe or socket is shared with every fd of the same open file
+/// description. So if stdout or stderr is the same socket, `println!`, `eprintln!` and the
+/// message of a panic may fail with `WouldBlock` while a `Stdin`, [`Stdout`] or [`Stderr`]
+/// exists, and `println!` panics then. Write through [`Stdout`] instead.
+///
+/// Only one `Stdin` can exist in a runtime at a time.
+pub fn stdin() -> io::Result<Stdin> {
+    let inner = match Nonblocking::new(STDIN_FILENO)? {
+        Some(fd) => StdinInner::Nonblocking(fd),
+        None => {
+            let (r, w) = pipe2(OFlag::O_CLOEXEC)?;
+            // SAFETY: both fds are just created by `pipe2` and owned by nobody else.
+            let (r, w) = unsafe { (OwnedFd::from_raw_fd(r), OwnedFd::from_raw_fd(w)) };
+            thread::Builder::new()
+                .name("simple-runtime-stdin".into())
+                .spawn(move || {
+                    let mut stdin = io::stdin().lock();
+                    let mut w = std::fs::File::from(w);
+                    // stop on EOF, or when the reader is dropped and the pipe is broken
+                    let _ = io::copy(&mut stdin, &mut w);
+                })?;
+            StdinInner::Offload(PipeReader::new(r)?)
+        }
+    };
+    Ok(Stdin { inner })
+}
+
+impl AsyncRead for Stdin {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            StdinInner::Nonblocking(fd) => poll_read_fd(fd.fd(), cx, buf),
+            StdinInner::Offload(r) => Pin::new(r).poll_read(cx, buf),
+        }
+    }
+}
+
+/// The standard output or error, see [`stdout`] and [`stderr`].
+///
+/// Pipes and sockets are written in nonblocking mode. Terminals, regular files and the like
+/// are written directly, writing them never waits for long.
+///
+/// On a pipe or socket, `print!` and the like may fail while it exists, see [`stdin`].
+pub struct Stdout {
+    fd: RawFd,
+    nonblocking: Option<Nonblocking>,
+}
+
+/// The standard error, it behaves the same as [`Stdout`].
+pub type Stderr = Stdout;
+
+/// Returns the standard output of the current process.
+///
+/// Only one `Stdout` can exist in a runtime at a time.
+pub fn stdout() -> io::Result<Stdout> {
+    Stdout::new(STDOUT_FILENO)
+}
+
+/// Returns the standard error of the current process.
+///
+/// Only one `Stderr` can exist in a runtime at a time.
+pub fn stderr() -> io::Result<Stderr> {
+    Stdout::new(STDERR_FILENO)
+}
+
+impl Stdout {
+    fn new(fd: RawFd) -> io::Result<Self> {
+        Ok(Self {
+            fd,
+            nonblocking: Nonblocking::new(fd)?,
+        })
+    }
+}
+
+impl AsyncWrite for Stdout {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &self.nonblocking {
+            Some(fd) => poll_write_fd(fd.fd(), cx, bufs),
+            None => Poll::Ready(writev(self.fd, bufs).map_err(Into::into)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,4 +1,11 @@
+pub mod async_fd;
 pub mod executor;
 mod helper;
+pub mod io;
+pub mod pty;
 mod reactor;
+pub mod runtime;
 pub mod tcp;
+pub mod unix;
+pub mod watch;
+pub mod work_stealing;
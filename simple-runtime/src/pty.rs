@@ -0,0 +1,162 @@
+use std::io::{self, IoSlice};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::pin::Pin;
+use std::process::{Child, Command, Stdio};
+use std::task::{Context, Poll};
+
+use futures::{AsyncRead, AsyncWrite};
+use nix::errno::Errno;
+use nix::fcntl::FcntlArg::F_SETFD;
+use nix::fcntl::{fcntl, FdFlag};
+use nix::libc;
+use nix::pty::{openpty, Winsize};
+use nix::unistd::setsid;
+
+use crate::async_fd::AsyncFd;
+use crate::io::{poll_read_fd, poll_write_fd};
+
+/// Open a pseudo-terminal pair with the given window size.
+///
+/// The master is registered with the reactor of the current runtime, the slave is the terminal
+/// of the child spawned with [`PtySlave::spawn`].
+pub fn open(rows: u16, cols: u16) -> io::Result<(PtyMaster, PtySlave)> {
+    let ws = winsize(rows, cols);
+    let pty = openpty(&ws, None)?;
+    // SAFETY: both fds are just created by `openpty` and owned by nobody else.
+    let (master, slave) = unsafe {
+        (
+            OwnedFd::from_raw_fd(pty.master),
+            OwnedFd::from_raw_fd(pty.slave),
+        )
+    };
+    // `openpty` doesn't set `O_CLOEXEC`, the children must not inherit the master
+    fcntl(master.as_raw_fd(), F_SETFD(FdFlag::FD_CLOEXEC))?;
+    fcntl(slave.as_raw_fd(), F_SETFD(FdFlag::FD_CLOEXEC))?;
+
+    Ok((
+        PtyMaster {
+            fd: AsyncFd::new(master)?,
+        },
+        PtySlave { fd: slave },
+    ))
+}
+
+fn winsize(rows: u16, cols: u16) -> Winsize {
+    Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+/// The master side of a pseudo-terminal, reading it gets the output of the child, writing it is
+/// the input of the child.
+pub struct PtyMaster {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl PtyMaster {
+    /// Set the window size, the foreground process group of the terminal gets `SIGWINCH`.
+    pub fn set_window_size(&self, rows: u16, cols: u16) -> io::Result<()> {
+        let ws = winsize(rows, cols);
+        // SAFETY: `TIOCSWINSZ` only reads the `winsize` passed in.
+        let ret = unsafe { libc::ioctl(self.fd.as_raw_fd(), libc::TIOCSWINSZ, &ws) };
+        Errno::result(ret)?;
+        Ok(())
+    }
+
+    /// Returns the window size as `(rows, cols)`.
+    pub fn window_size(&self) -> io::Result<(u16, u16)> {
+        // SAFETY: `winsize` is plain old data, and is filled by `TIOCGWINSZ`.
+        let mut ws: Winsize = unsafe { mem::zeroed() };
+        let ret = unsafe { libc::ioctl(self.fd.as_raw_fd(), libc::TIOCGWINSZ, &mut ws) };
+        Errno::result(ret)?;
+        Ok((ws.ws_row, ws.ws_col))
+    }
+}
+
+impl AsRawFd for PtyMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match poll_read_fd(&self.fd, cx, buf) {
+            // linux fails with EIO once every fd of the slave is closed, that's the EOF of a pty
+            Poll::Ready(Err(e)) if e.raw_os_error() == Some(Errno::EIO as i32) => {
+                Poll::Ready(Ok(0))
+            }
+            ret => ret,
+        }
+    }
+}
+
+impl AsyncWrite for PtyMaster {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        poll_write_fd(&self.fd, cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        poll_write_fd(&self.fd, cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // the child sees the hangup once the master is dropped
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The slave side of a pseudo-terminal.
+pub struct PtySlave {
+    fd: OwnedFd,
+}
+
+impl PtySlave {
+    /// Spawn `cmd` in a new session, with the slave as its controlling terminal and stdio.
+    ///
+    /// The slave is closed in this process afterwards, so reading the master gets EOF once the
+    /// child and its descendants close the terminal.
+    pub fn spawn(self, cmd: &mut Command) -> io::Result<Child> {
+        cmd.stdin(Stdio::from(self.fd.try_clone()?))
+            .stdout(Stdio::from(self.fd.try_clone()?))
+            .stderr(Stdio::from(self.fd.try_clone()?));
+
+        // SAFETY: only async-signal-safe syscalls are made between fork and exec.
+        unsafe {
+            cmd.pre_exec(|| {
+                setsid()?;
+                // stdin is the slave now
+                Errno::result(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+                Ok(())
+            });
+        }
+        cmd.spawn()
+    }
+}
+
+impl AsRawFd for PtySlave {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
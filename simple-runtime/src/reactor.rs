@@ -1,56 +1,215 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::os::fd::RawFd;
-use std::rc::Rc;
-use std::task::{Context, Waker};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 
+use nix::errno::Errno;
 use nix::fcntl::FcntlArg::{F_GETFL, F_SETFL};
 use nix::fcntl::{fcntl, OFlag};
-use polling::{Event, Poller};
+use nix::libc;
+use nix::sys::epoll::{epoll_ctl, EpollEvent, EpollFlags, EpollOp};
+use polling::{Event, PollMode, Poller};
 
 use crate::executor::EX;
+use crate::work_stealing;
 
+/// Returns the reactor of the current `block_on` or work-stealing worker, if any.
 #[inline]
-pub(crate) fn get_reactor() -> Rc<RefCell<Reactor>> {
-    EX.with(|ex| ex.reactor.clone())
+pub(crate) fn try_get_reactor() -> Option<Arc<Reactor>> {
+    if EX.is_set() {
+        Some(EX.with(|ex| ex.reactor.clone()))
+    } else {
+        work_stealing::current_reactor()
+    }
+}
+
+/// # Panics
+///
+/// This function will panic if it is called outside a runtime.
+#[inline]
+pub(crate) fn get_reactor() -> Arc<Reactor> {
+    try_get_reactor().expect("must be called inside a simple-runtime executor")
+}
+
+/// The readiness of a fd, including the conditions epoll reports besides readable and writable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Readiness {
+    pub readable: bool,
+    pub writable: bool,
+    /// the peer has shut down its writing half, `EPOLLRDHUP`.
+    pub read_closed: bool,
+    /// both halves are closed, `EPOLLHUP`.
+    pub hangup: bool,
+    /// an error is pending on the fd, `EPOLLERR`.
+    pub error: bool,
+}
+
+impl Readiness {
+    /// Returns the current readiness of `fd` without waiting.
+    pub fn of(fd: RawFd) -> io::Result<Self> {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN | libc::POLLOUT | libc::POLLRDHUP,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is valid for the call, and the timeout is zero.
+        Errno::result(unsafe { libc::poll(&mut pollfd, 1, 0) })?;
+
+        let revents = pollfd.revents;
+        Ok(Self {
+            readable: revents & libc::POLLIN != 0,
+            writable: revents & libc::POLLOUT != 0,
+            read_closed: revents & libc::POLLRDHUP != 0,
+            hangup: revents & libc::POLLHUP != 0,
+            error: revents & libc::POLLERR != 0,
+        })
+    }
+
+    /// whether the peer is gone or the fd is broken, reading it won't block.
+    pub fn is_closed(&self) -> bool {
+        self.read_closed || self.hangup || self.error
+    }
+}
+
+/// the token of the closed interest, it never collides with `fd * 2` or `fd * 2 + 1`.
+fn closed_token(fd: RawFd) -> u64 {
+    1 << 63 | fd as u64
+}
+
+/// A `Send` handle to wake up the reactor blocking in [`Reactor::wait`] from another thread.
+#[derive(Clone)]
+pub(crate) struct Notifier(Arc<Poller>);
+
+impl Notifier {
+    pub(crate) fn notify(&self) {
+        self.0.notify().unwrap();
+    }
+}
+
+/// How the reactor registers fds with epoll.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReactorMode {
+    /// an fd is armed for the directions waited for on every `WouldBlock`, and disarmed by the
+    /// event, which costs an `epoll_ctl` each time.
+    Oneshot,
+    /// an fd is registered once for both directions, the events are cached as readiness bits
+    /// until a task waits for them, and the `WouldBlock` that follows clears them. It saves
+    /// the `epoll_ctl` of every `WouldBlock`.
+    #[default]
+    EdgeTriggered,
+}
+
+/// The syscalls made by a reactor so far, to compare the [`ReactorMode`]s.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReactorStats {
+    /// the `epoll_ctl` calls to add, modify or delete fds.
+    pub ctl_calls: u64,
+    /// the `epoll_wait` calls.
+    pub wait_calls: u64,
 }
 
+/// The reactor can be shared between threads, only one thread is blocking in [`Reactor::wait`] at
+/// a time, and others register interests meanwhile.
 pub struct Reactor {
-    poller: Poller,
-    waker_map: HashMap<u64, Waker>,
-    buffer: Vec<Event>,
+    poller: Arc<Poller>,
+    // every task waiting for the same fd and direction is woken, e.g. two accept loops sharing a
+    // listener
+    waker_map: Mutex<HashMap<u64, Vec<Waker>>>,
+    // the tokens whose edge came while nobody waited, only used in edge-triggered mode and only
+    // locked while holding `waker_map`
+    ready: Mutex<HashSet<u64>>,
+    buffer: Mutex<Vec<Event>>,
+    mode: ReactorMode,
+    ctl_calls: AtomicU64,
+    wait_calls: AtomicU64,
 }
 
 impl Reactor {
-    fn new() -> Self {
+    /// falls back to oneshot mode if the poller doesn't support edge-triggered mode.
+    pub(crate) fn new(mode: ReactorMode) -> Self {
+        let poller = Poller::new().unwrap();
+        let mode = match mode {
+            ReactorMode::EdgeTriggered if !poller.supports_edge() => ReactorMode::Oneshot,
+            mode => mode,
+        };
         Self {
-            poller: Poller::new().unwrap(),
+            poller: Arc::new(poller),
             waker_map: Default::default(),
-            buffer: Vec::with_capacity(2048),
+            ready: Default::default(),
+            buffer: Mutex::new(Vec::with_capacity(2048)),
+            mode,
+            ctl_calls: AtomicU64::new(0),
+            wait_calls: AtomicU64::new(0),
         }
     }
 
+    pub(crate) fn stats(&self) -> ReactorStats {
+        ReactorStats {
+            ctl_calls: self.ctl_calls.load(Ordering::Relaxed),
+            wait_calls: self.wait_calls.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn notifier(&self) -> Notifier {
+        Notifier(self.poller.clone())
+    }
+
     /// add fd to epoll, and interest none event.
     ///
-    /// the `polling` crate use oneshot mode by default, so we need re-register each event every
-    /// time after it is triggered.
-    pub fn add(&mut self, fd: RawFd) {
-        let mut flags = OFlag::from_bits(fcntl(fd, F_GETFL).unwrap()).unwrap();
+    /// in oneshot mode we need re-register each event every time after it is triggered, in
+    /// edge-triggered mode the fd is registered for both directions here once and for all.
+    pub fn add(&self, fd: RawFd) {
+        self.try_add(fd).unwrap();
+    }
+
+    /// the same as [`Reactor::add`], but returns an error instead of panicking, e.g. for fds
+    /// that epoll doesn't support like regular files.
+    pub fn try_add(&self, fd: RawFd) -> io::Result<()> {
+        let mut flags = OFlag::from_bits_truncate(fcntl(fd, F_GETFL)?);
         flags |= OFlag::O_NONBLOCK;
         // set fd as nonblock
-        fcntl(fd, F_SETFL(flags)).unwrap();
-        // add fd to epoll, and interest none event.
-        self.poller.add(fd, Event::none(fd as _)).unwrap();
+        fcntl(fd, F_SETFL(flags))?;
+        self.ctl_calls.fetch_add(1, Ordering::Relaxed);
+        match self.mode {
+            // add fd to epoll, and interest none event.
+            ReactorMode::Oneshot => self.poller.add(fd, Event::none(fd as _)),
+            ReactorMode::EdgeTriggered => {
+                self.poller
+                    .add_with_mode(fd, Event::all(fd as _), PollMode::Edge)
+            }
+        }
+    }
+
+    /// delete wakers of fd and remove it from epoll, the fd stays open.
+    pub fn remove(&self, fd: RawFd) -> io::Result<()> {
+        self.delete(fd);
+        self.ctl_calls.fetch_add(1, Ordering::Relaxed);
+        self.poller.delete(fd)
     }
 
     /// delete waker from `waker_map` with fd
     ///
     /// We use a clever way of using `fd * 2` as the token for readability and `fd * 2 + 1` as the
     /// token for writability.
-    pub fn delete(&mut self, fd: RawFd) {
+    pub fn delete(&self, fd: RawFd) {
         // use fd * 2 as readable token, fd * 2 + 1 as writable token
-        self.waker_map.remove(&(fd as u64 * 2));
-        self.waker_map.remove(&(fd as u64 * 2 + 1));
+        let mut waker_map = self.waker_map.lock().unwrap();
+        let wakers = (
+            waker_map.remove(&(fd as u64 * 2)),
+            waker_map.remove(&(fd as u64 * 2 + 1)),
+            waker_map.remove(&closed_token(fd)),
+        );
+        // the fd number may be reused by the next registration
+        let mut ready = self.ready.lock().unwrap();
+        ready.remove(&(fd as u64 * 2));
+        ready.remove(&(fd as u64 * 2 + 1));
+        drop(ready);
+        // dropping a waker may drop the last reference of a task and its IO objects, which calls
+        // `delete` again, so the lock must be released first.
+        drop(waker_map);
+        drop(wakers);
     }
 
     /// Wait for an event to occur on a file descriptor.
@@ -60,47 +219,186 @@ impl Reactor {
     ///
     /// We use a clever way of using `fd * 2` as the token for readability and `fd * 2 + 1` as the
     /// token for writability.
-    pub fn wait(&mut self) {
-        self.poller.wait(&mut self.buffer, None).unwrap();
-
-        for _ in 0..self.buffer.len() {
-            let event = self.buffer.swap_remove(0);
+    pub fn wait(&self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        self.wait_calls.fetch_add(1, Ordering::Relaxed);
+        self.poller.wait(&mut buffer, None).unwrap();
 
-            if event.readable {
-                if let Some(waker) = self.waker_map.remove(&(event.key as u64 * 2)) {
-                    waker.wake();
+        let mut wakers: Vec<Waker> = Vec::with_capacity(buffer.len());
+        let mut waker_map = self.waker_map.lock().unwrap();
+        let mut ready = self.ready.lock().unwrap();
+        for event in buffer.drain(..) {
+            for (token, triggered) in [
+                (event.key as u64 * 2, event.readable),
+                (event.key as u64 * 2 + 1, event.writable),
+            ] {
+                if !triggered {
+                    continue;
+                }
+                match waker_map.remove(&token) {
+                    Some(waiters) => wakers.extend(waiters),
+                    // an edge is reported only once, keep it for the next waiter
+                    None if self.mode == ReactorMode::EdgeTriggered => {
+                        ready.insert(token);
+                    }
+                    None => {}
                 }
             }
 
-            if event.writable {
-                if let Some(waker) = self.waker_map.remove(&(event.key as u64 * 2 + 1)) {
-                    waker.wake();
-                }
+            // `polling` reports hangup and errors as readable, tell them apart from plain data
+            let closed = closed_token(event.key as _);
+            if event.readable
+                && waker_map.contains_key(&closed)
+                && Readiness::of(event.key as _).map_or(true, |r| r.is_closed())
+            {
+                wakers.extend(waker_map.remove(&closed).into_iter().flatten());
             }
+
+            // the oneshot event disabled the fd, re-arm the interests still waited for
+            let _ = self.arm(&waker_map, event.key as _);
         }
+        drop(ready);
+        drop(waker_map);
+        drop(buffer);
+
+        wakers.into_iter().for_each(Waker::wake);
     }
 
     /// interest readable event for fd, and save waker to waker_map
     ///
     /// We use a clever way of using `fd * 2` as the token for readability and `fd * 2 + 1` as the
     /// token for writability.
-    pub fn interest_readable(&mut self, fd: RawFd, cx: &mut Context) {
-        self.poller.modify(fd, Event::readable(fd as _)).unwrap();
-        self.waker_map.insert(fd as u64 * 2, cx.waker().clone());
+    pub fn interest_readable(&self, fd: RawFd, cx: &mut Context) {
+        self.interest(fd, fd as u64 * 2, cx);
     }
 
     /// interest writable event for fd, and save waker to waker_map
     ///
     /// We use a clever way of using `fd * 2` as the token for readability and `fd * 2 + 1` as the
     /// token for writability.
-    pub fn interest_writable(&mut self, fd: RawFd, cx: &mut Context) {
-        self.poller.modify(fd, Event::writable(fd as _)).unwrap();
-        self.waker_map.insert(fd as u64 * 2 + 1, cx.waker().clone());
+    pub fn interest_writable(&self, fd: RawFd, cx: &mut Context) {
+        self.interest(fd, fd as u64 * 2 + 1, cx);
+    }
+
+    /// interest the peer closing or an error on fd, and save waker to waker_map
+    ///
+    /// The waker is only woken for [`Readiness::is_closed`], not when data arrives.
+    pub fn interest_closed(&self, fd: RawFd, cx: &mut Context) {
+        self.interest(fd, closed_token(fd), cx);
+    }
+
+    /// Returns the readiness of fd if it is closed, otherwise interest the closing.
+    pub fn poll_closed(&self, fd: RawFd, cx: &mut Context) -> Poll<io::Result<Readiness>> {
+        match Readiness::of(fd) {
+            Ok(readiness) if readiness.is_closed() => Poll::Ready(Ok(readiness)),
+            Ok(_) => {
+                self.interest_closed(fd, cx);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// whether any task waits for an event, otherwise `wait` could only be woken by a notifier.
+    pub(crate) fn has_waiters(&self) -> bool {
+        !self.waker_map.lock().unwrap().is_empty()
+    }
+
+    /// whether a readable interest of fd is registered and its event has not been triggered yet.
+    pub fn is_pending_readable(&self, fd: RawFd) -> bool {
+        self.waker_map
+            .lock()
+            .unwrap()
+            .contains_key(&(fd as u64 * 2))
+    }
+
+    /// whether a writable interest of fd is registered and its event has not been triggered yet.
+    pub fn is_pending_writable(&self, fd: RawFd) -> bool {
+        self.waker_map
+            .lock()
+            .unwrap()
+            .contains_key(&(fd as u64 * 2 + 1))
+    }
+
+    /// save the waker and arm the fd.
+    ///
+    /// In edge-triggered mode, if the edge of the token came while nobody waited, the waker is
+    /// woken at once instead, and the readiness bit is cleared. The `WouldBlock` may be older
+    /// than the edge, so the task must try the IO again, and it waits for real if that would
+    /// block too.
+    ///
+    /// The waker is added to the waiters of the token unless one of them would wake the same task,
+    /// so polling a future again doesn't pile up wakers.
+    ///
+    /// The waker is saved before arming the fd, otherwise another thread blocking in `wait` may see
+    /// the event before the waker is there.
+    fn interest(&self, fd: RawFd, token: u64, cx: &mut Context) {
+        let mut waker_map = self.waker_map.lock().unwrap();
+        if self.mode == ReactorMode::EdgeTriggered && self.ready.lock().unwrap().remove(&token) {
+            drop(waker_map);
+            cx.waker().wake_by_ref();
+            return;
+        }
+        let waiters = waker_map.entry(token).or_default();
+        if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            waiters.push(cx.waker().clone());
+        }
+        self.arm(&waker_map, fd).unwrap();
+    }
+
+    /// `modify` replaces the interest of the whole fd, so we always arm the fd with every
+    /// direction that has a waker, or a read and a write waiting at the same time would disarm
+    /// each other.
+    ///
+    /// In edge-triggered mode the fd stays registered for both directions, there is nothing to
+    /// arm.
+    fn arm(&self, waker_map: &HashMap<u64, Vec<Waker>>, fd: RawFd) -> io::Result<()> {
+        if self.mode == ReactorMode::EdgeTriggered {
+            return Ok(());
+        }
+        let readable = waker_map.contains_key(&(fd as u64 * 2));
+        let writable = waker_map.contains_key(&(fd as u64 * 2 + 1));
+        let closed = waker_map.contains_key(&closed_token(fd));
+        if !readable && !writable && !closed {
+            return Ok(());
+        }
+        self.ctl_calls.fetch_add(1, Ordering::Relaxed);
+        if closed {
+            return self.arm_closed(fd, readable, writable);
+        }
+        let interest = Event {
+            key: fd as _,
+            readable,
+            writable,
+        };
+        self.poller.modify(fd, interest)
+    }
+
+    /// `polling` can't express `EPOLLRDHUP` without `EPOLLIN`, which would fire again and again
+    /// while unread data is there, so the fd is modified on the epoll fd of the poller directly.
+    /// Hangup and errors are always reported by epoll.
+    fn arm_closed(&self, fd: RawFd, readable: bool, writable: bool) -> io::Result<()> {
+        let mut flags = EpollFlags::EPOLLONESHOT | EpollFlags::EPOLLRDHUP;
+        if readable {
+            flags |= EpollFlags::EPOLLIN | EpollFlags::EPOLLPRI;
+        }
+        if writable {
+            flags |= EpollFlags::EPOLLOUT;
+        }
+        // `polling` uses the key as the epoll data, the key is the fd here
+        let mut event = EpollEvent::new(flags, fd as u64);
+        epoll_ctl(
+            self.poller.as_raw_fd(),
+            EpollOp::EpollCtlMod,
+            fd,
+            &mut event,
+        )?;
+        Ok(())
     }
 }
 
 impl Default for Reactor {
     fn default() -> Self {
-        Self::new()
+        Self::new(ReactorMode::default())
     }
 }
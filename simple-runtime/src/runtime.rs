@@ -0,0 +1,257 @@
+use std::cell::Cell;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::task::Poll;
+use std::thread::{self, JoinHandle};
+
+use futures::future::poll_fn;
+use futures::Future;
+use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+
+use crate::executor::{Executor, Injector, ReactorMode};
+
+thread_local! {
+    static CORE_ID: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Returns the id of the core the current thread belongs to, or `None` outside a [`Runtime`].
+pub fn current_core() -> Option<usize> {
+    CORE_ID.with(|id| id.get())
+}
+
+/// pin the current thread to the `n`-th cpu the process is allowed to run on, wrapping around
+/// if there are more cores than cpus.
+fn pin_to_cpu(n: usize) -> nix::Result<()> {
+    let allowed = sched_getaffinity(Pid::from_raw(0))?;
+    let cpus: Vec<_> = (0..CpuSet::count())
+        .filter(|&cpu| allowed.is_set(cpu).unwrap_or(false))
+        .collect();
+
+    let mut cpu_set = CpuSet::new();
+    cpu_set.set(cpus[n % cpus.len()])?;
+    sched_setaffinity(Pid::from_raw(0), &cpu_set)
+}
+
+/// Configure and launch a thread-per-core [`Runtime`].
+///
+/// Each core is a thread with its own [`Executor`] and `Reactor`, tasks never move between
+/// cores, so they don't need to be `Send`.
+pub struct Builder {
+    worker_threads: usize,
+    pin_cores: bool,
+    thread_name: String,
+    reactor_mode: ReactorMode,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            worker_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            pin_cores: false,
+            thread_name: "simple-runtime-core".into(),
+            reactor_mode: ReactorMode::default(),
+        }
+    }
+
+    /// the number of cores (threads), defaults to the available parallelism.
+    pub fn worker_threads(mut self, n: usize) -> Self {
+        self.worker_threads = n.max(1);
+        self
+    }
+
+    /// pin the thread of each core to its own cpu with `sched_setaffinity`.
+    pub fn pin_cores(mut self, pin: bool) -> Self {
+        self.pin_cores = pin;
+        self
+    }
+
+    /// the thread name prefix, the core id is appended to it.
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// how the reactor of each core registers fds, defaults to edge-triggered.
+    pub fn reactor_mode(mut self, mode: ReactorMode) -> Self {
+        self.reactor_mode = mode;
+        self
+    }
+
+    pub fn build(self) -> io::Result<Runtime> {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let mut injectors = Vec::with_capacity(self.worker_threads);
+        let mut threads = Vec::with_capacity(self.worker_threads);
+
+        for core_id in 0..self.worker_threads {
+            let (tx, rx) = mpsc::channel();
+            let stop = shutdown.clone();
+            let pin_cores = self.pin_cores;
+            let reactor_mode = self.reactor_mode;
+
+            let thread = thread::Builder::new()
+                .name(format!("{}-{core_id}", self.thread_name))
+                .spawn(move || {
+                    if pin_cores {
+                        pin_to_cpu(core_id)?;
+                    }
+                    CORE_ID.with(|id| id.set(Some(core_id)));
+
+                    let ex = Executor::with_reactor_mode(reactor_mode);
+                    // the injector is the only way to reach this core from outside
+                    tx.send(ex.injector.clone()).unwrap();
+
+                    // the root future is polled on each loop of `block_on`, and `shutdown` always
+                    // notifies the reactor after the flag is set.
+                    ex.block_on(|| {
+                        let stop = stop.clone();
+                        poll_fn(move |_| {
+                            if stop.load(Ordering::Acquire) {
+                                Poll::Ready(())
+                            } else {
+                                Poll::Pending
+                            }
+                        })
+                    });
+                    Ok(())
+                })?;
+
+            match rx.recv() {
+                Ok(injector) => injectors.push(injector),
+                // the thread exited before sending its injector, e.g. failed to pin the core
+                Err(_) => {
+                    // dropping the partial runtime stops the cores already started
+                    drop(Runtime {
+                        handle: Handle {
+                            injectors: injectors.into(),
+                            shutdown,
+                        },
+                        threads,
+                    });
+                    let err = thread.join().unwrap().err();
+                    return Err(err.map_or_else(|| io::Error::other("core exited"), Into::into));
+                }
+            }
+            threads.push(thread);
+        }
+
+        Ok(Runtime {
+            handle: Handle {
+                injectors: injectors.into(),
+                shutdown,
+            },
+            threads,
+        })
+    }
+}
+
+/// A cloneable, `Send` handle to spawn work onto the cores of a [`Runtime`].
+#[derive(Clone)]
+pub struct Handle {
+    injectors: Arc<[Arc<Injector>]>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Handle {
+    /// the number of cores
+    pub fn cores(&self) -> usize {
+        self.injectors.len()
+    }
+
+    /// Run `f` on core `core_id` and spawn the future it returns onto that core's executor.
+    ///
+    /// Only the closure crosses threads, the future is created on the target core, so it doesn't
+    /// need to be `Send`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `core_id` is out of range.
+    pub fn spawn_on<F, T>(&self, core_id: usize, f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        self.injectors[core_id].push(Box::new(move || Executor::spawn(f())));
+    }
+
+    /// Run a clone of `f` on every core, e.g. to start a `SO_REUSEPORT` listener per core.
+    pub fn spawn_on_all<F, T>(&self, f: F)
+    where
+        F: FnOnce() -> T + Clone + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        for core_id in 0..self.cores() {
+            self.spawn_on(core_id, f.clone());
+        }
+    }
+
+    /// Ask all cores to stop, tasks still pending are dropped.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Release);
+        self.injectors.iter().for_each(|i| i.notify());
+    }
+}
+
+/// A thread-per-core runtime, see [`Builder`].
+pub struct Runtime {
+    handle: Handle,
+    threads: Vec<JoinHandle<nix::Result<()>>>,
+}
+
+impl Runtime {
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// see [`Handle::spawn_on`]
+    pub fn spawn_on<F, T>(&self, core_id: usize, f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        self.handle.spawn_on(core_id, f)
+    }
+
+    /// see [`Handle::spawn_on_all`]
+    pub fn spawn_on_all<F, T>(&self, f: F)
+    where
+        F: FnOnce() -> T + Clone + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        self.handle.spawn_on_all(f)
+    }
+
+    /// Block until all cores stopped, which happens after [`Handle::shutdown`] is called.
+    pub fn join(mut self) {
+        self.join_threads();
+    }
+
+    /// Stop all cores and wait for their threads to exit.
+    pub fn shutdown(self) {
+        drop(self);
+    }
+
+    fn join_threads(&mut self) {
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        self.join_threads();
+    }
+}
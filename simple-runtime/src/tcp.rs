@@ -1,30 +1,104 @@
-use std::cell::RefCell;
-use std::io::{self, ErrorKind, Read, Write};
+use std::fs::File;
+use std::io::{self, ErrorKind, IoSlice, IoSliceMut, Read, Write};
 use std::net::{
     Shutdown, SocketAddr, TcpListener as StdTcpListener, TcpStream as StdTcpStream, ToSocketAddrs,
 };
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::pin::Pin;
-use std::rc::{Rc, Weak};
+use std::sync::{Arc, Weak};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+use futures::future::poll_fn;
 use futures::{AsyncRead, AsyncWrite, Stream};
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use nix::errno::Errno;
+use nix::fcntl::{self, OFlag, SpliceFFlags};
+use nix::libc::off_t;
+use nix::sys::sendfile;
+use nix::unistd::pipe2;
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
 
-use crate::reactor::{get_reactor, Reactor};
+use crate::reactor::{get_reactor, try_get_reactor, Reactor};
 
-pub struct TcpListener {
-    listener: StdTcpListener,
-    reactor: Weak<RefCell<Reactor>>,
+pub use socket2::TcpKeepalive;
+
+/// A builder to configure a TCP socket before it becomes a [`TcpListener`] or a [`TcpStream`].
+///
+/// The defaults match [`TcpListener::bind`]: `SO_REUSEADDR` is enabled and the backlog is 1024.
+#[derive(Debug, Clone)]
+pub struct TcpSocket {
+    reuseaddr: bool,
+    reuseport: bool,
+    backlog: i32,
+    only_v6: Option<bool>,
+    device: Option<Vec<u8>>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
 }
 
-impl TcpListener {
-    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, io::Error> {
-        let addr = addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "empty address"))?;
+impl Default for TcpSocket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl TcpSocket {
+    pub fn new() -> Self {
+        Self {
+            reuseaddr: true,
+            reuseport: false,
+            backlog: 1024,
+            only_v6: None,
+            device: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+
+    /// set `SO_REUSEADDR`, enabled by default.
+    pub fn reuseaddr(mut self, reuseaddr: bool) -> Self {
+        self.reuseaddr = reuseaddr;
+        self
+    }
+
+    /// set `SO_REUSEPORT`, so multiple sockets can bind the same address and port.
+    pub fn reuseport(mut self, reuseport: bool) -> Self {
+        self.reuseport = reuseport;
+        self
+    }
+
+    /// the maximum length of the pending connections queue, 1024 by default.
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog.min(i32::MAX as u32) as i32;
+        self
+    }
+
+    /// set `IPV6_V6ONLY`, only applied to IPv6 addresses.
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// bind the socket to a network interface by name with `SO_BINDTODEVICE`, e.g. `eth0`.
+    pub fn bind_device(mut self, interface: &str) -> Self {
+        self.device = Some(interface.as_bytes().to_vec());
+        self
+    }
+
+    /// set `SO_RCVBUF`.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// set `SO_SNDBUF`.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// create a socket for `addr` with all options applied.
+    fn socket(&self, addr: &SocketAddr) -> io::Result<Socket> {
         let domain = if addr.is_ipv6() {
             Domain::IPV6
         } else {
@@ -33,20 +107,129 @@ impl TcpListener {
 
         let sk = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
 
-        let addr = SockAddr::from(addr);
+        sk.set_reuse_address(self.reuseaddr)?;
+        sk.set_reuse_port(self.reuseport)?;
+        if let (Some(only_v6), true) = (self.only_v6, addr.is_ipv6()) {
+            sk.set_only_v6(only_v6)?;
+        }
+        if let Some(device) = &self.device {
+            sk.bind_device(Some(device))?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            sk.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            sk.set_send_buffer_size(size)?;
+        }
+
+        Ok(sk)
+    }
 
-        sk.set_reuse_address(true)?;
-        sk.bind(&addr)?;
-        sk.listen(1024)?;
+    /// bind to `addr` and start listening.
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpListener> {
+        let addr = first_addr(addr)?;
+        let sk = self.socket(&addr)?;
 
-        let reactor = get_reactor();
-        reactor.borrow_mut().add(sk.as_raw_fd());
+        sk.bind(&SockAddr::from(addr))?;
+        sk.listen(self.backlog)?;
 
+        Ok(TcpListener::from(StdTcpListener::from(sk)))
+    }
+
+    /// connect to `addr`, resolves once the connection is established.
+    pub async fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpStream> {
+        let addr = first_addr(addr)?;
+        let sk = self.socket(&addr)?;
+
+        sk.set_nonblocking(true)?;
+        match sk.connect(&SockAddr::from(addr)) {
+            Ok(()) => {}
+            Err(ref e) if e.raw_os_error() == Some(Errno::EINPROGRESS as i32) => {}
+            Err(e) => return Err(e),
+        }
+
+        let stream = TcpStream::from(StdTcpStream::from(sk));
+        // the socket becomes writable when the connection is established or failed.
+        poll_fn(|cx| {
+            if let Some(e) = stream.stream.take_error()? {
+                return Poll::Ready(Err(e));
+            }
+            match stream.stream.peer_addr() {
+                Ok(_) => Poll::Ready(Ok(())),
+                Err(ref e) if e.kind() == ErrorKind::NotConnected => {
+                    get_reactor().interest_writable(stream.stream.as_raw_fd(), cx);
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(e)),
+            }
+        })
+        .await?;
+
+        Ok(stream)
+    }
+}
+
+fn first_addr<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("empty address"))
+}
+
+pub struct TcpListener {
+    listener: StdTcpListener,
+    reactor: Weak<Reactor>,
+}
+
+impl TcpListener {
+    /// bind to `addr` with the default [`TcpSocket`] options.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, io::Error> {
+        TcpSocket::new().listen(addr)
+    }
+
+    /// bind to `addr` with `SO_REUSEPORT`, so each core of a [`Runtime`] can have its own
+    /// listener on the same address, the kernel balances incoming connections between them.
+    ///
+    /// [`Runtime`]: crate::runtime::Runtime
+    pub fn bind_reuseport<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        TcpSocket::new().reuseport(true).listen(addr)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// register a listener created elsewhere, e.g. an fd inherited from the parent process or
+    /// received with [`UnixStream::recv_fds`](crate::unix::UnixStream::recv_fds).
+    ///
+    /// Unlike the `From` impl, it returns an error if the fd can't be registered.
+    pub fn from_std(listener: StdTcpListener) -> io::Result<Self> {
+        let reactor = get_reactor();
+        reactor.try_add(listener.as_raw_fd())?;
         Ok(Self {
-            listener: sk.into(),
-            reactor: Rc::downgrade(&reactor),
+            listener,
+            reactor: Arc::downgrade(&reactor),
         })
     }
+
+    /// deregister the listener from the reactor, so it can be handed to another process, it
+    /// stays in nonblocking mode.
+    pub fn into_std(self) -> io::Result<StdTcpListener> {
+        if let Some(reactor) = self.reactor.upgrade() {
+            reactor.remove(self.listener.as_raw_fd())?;
+        }
+        Ok(self.listener)
+    }
+}
+
+impl From<StdTcpListener> for TcpListener {
+    fn from(value: StdTcpListener) -> Self {
+        let reactor = get_reactor();
+        reactor.add(value.as_raw_fd());
+        Self {
+            listener: value,
+            reactor: Arc::downgrade(&reactor),
+        }
+    }
 }
 
 impl Stream for TcpListener {
@@ -57,9 +240,7 @@ impl Stream for TcpListener {
             Ok((stream, addr)) => Poll::Ready(Some(Ok((stream.into(), addr)))),
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                 let reactor = self.reactor.upgrade().unwrap();
-                reactor
-                    .borrow_mut()
-                    .interest_readable(self.listener.as_raw_fd(), cx);
+                reactor.interest_readable(self.listener.as_raw_fd(), cx);
                 Poll::Pending
             }
             Err(e) => Poll::Ready(Some(Err(e))),
@@ -67,22 +248,212 @@ impl Stream for TcpListener {
     }
 }
 
+/// The capacity of a pipe by default, we move at most this many bytes per `splice` call.
+const SPLICE_CHUNK: usize = 64 * 1024;
+
 pub struct TcpStream {
     stream: StdTcpStream,
 }
 
+impl TcpStream {
+    /// connect to `addr` with the default [`TcpSocket`] options.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        TcpSocket::new().connect(addr).await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.stream.nodelay()
+    }
+
+    /// set `TCP_NODELAY`, disable the Nagle algorithm if `true`.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    pub fn keepalive(&self) -> io::Result<bool> {
+        SockRef::from(&self.stream).keepalive()
+    }
+
+    /// enable `SO_KEEPALIVE` with the given parameters, or disable it with `None`.
+    pub fn set_keepalive(&self, keepalive: Option<&TcpKeepalive>) -> io::Result<()> {
+        let sk = SockRef::from(&self.stream);
+        match keepalive {
+            Some(params) => sk.set_tcp_keepalive(params),
+            None => sk.set_keepalive(false),
+        }
+    }
+
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        SockRef::from(&self.stream).linger()
+    }
+
+    /// set `SO_LINGER`, `close` blocks until the pending data is sent or the timeout expires.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        SockRef::from(&self.stream).set_linger(linger)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.stream.ttl()
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.stream.set_ttl(ttl)
+    }
+
+    /// shut down the read, write, or both halves of the connection.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    /// receive data without removing it from the queue.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_peek(cx, buf)).await
+    }
+
+    pub fn poll_peek(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.stream.peek(buf) {
+            Ok(n) => Poll::Ready(Ok(n)),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                get_reactor().interest_readable(self.stream.as_raw_fd(), cx);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Waits for the peer to close the connection or an error, without reading any data.
+    ///
+    /// It completes once the peer shuts down its writing half, even if there is still data to
+    /// read.
+    pub async fn closed(&self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_peer_closed(cx)).await
+    }
+
+    pub fn poll_peer_closed(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        get_reactor()
+            .poll_closed(self.stream.as_raw_fd(), cx)
+            .map_ok(|_| ())
+    }
+
+    /// Send `count` bytes of `file` starting at `offset` to the peer with `sendfile(2)`.
+    ///
+    /// The data is copied inside the kernel and never enters user space. Returns the number of
+    /// bytes sent, which is less than `count` only if the end of file is reached.
+    pub async fn sendfile(&mut self, file: &File, offset: u64, count: usize) -> io::Result<usize> {
+        let mut offset = offset as off_t;
+        let mut sent = 0;
+        while sent < count {
+            let n =
+                poll_fn(|cx| self.poll_sendfile(cx, file.as_raw_fd(), &mut offset, count - sent))
+                    .await?;
+            if n == 0 {
+                break;
+            }
+            sent += n;
+        }
+        Ok(sent)
+    }
+
+    fn poll_sendfile(
+        &self,
+        cx: &mut Context<'_>,
+        in_fd: RawFd,
+        offset: &mut off_t,
+        count: usize,
+    ) -> Poll<io::Result<usize>> {
+        let fd = self.stream.as_raw_fd();
+        match sendfile::sendfile(fd, in_fd, Some(offset), count) {
+            Ok(n) => Poll::Ready(Ok(n)),
+            Err(Errno::EAGAIN) => {
+                get_reactor().interest_writable(fd, cx);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e.into())),
+        }
+    }
+
+    /// move at most `len` bytes from `fd_in` to `fd_out`, one of them must be a pipe.
+    ///
+    /// `self` is the socket side of the transfer, so when it would block we register `self` for
+    /// readability if it is `fd_in`, or for writability if it is `fd_out`.
+    fn poll_splice(
+        &self,
+        cx: &mut Context<'_>,
+        fd_in: RawFd,
+        fd_out: RawFd,
+        len: usize,
+    ) -> Poll<io::Result<usize>> {
+        let fd = self.stream.as_raw_fd();
+        let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+        match fcntl::splice(fd_in, None, fd_out, None, len, flags) {
+            Ok(n) => Poll::Ready(Ok(n)),
+            Err(Errno::EAGAIN) => {
+                let reactor = get_reactor();
+                if fd == fd_in {
+                    reactor.interest_readable(fd, cx);
+                } else {
+                    reactor.interest_writable(fd, cx);
+                }
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e.into())),
+        }
+    }
+}
+
+/// Copy all data from `reader` to `writer` until EOF, returns the number of bytes copied.
+///
+/// The data is moved with `splice(2)` through an intermediate pipe, so it never enters user
+/// space.
+pub async fn splice(reader: &mut TcpStream, writer: &mut TcpStream) -> io::Result<u64> {
+    let (r, w) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+    // SAFETY: both fds are just created by `pipe2` and owned by nobody else.
+    let (r, w) = unsafe { (OwnedFd::from_raw_fd(r), OwnedFd::from_raw_fd(w)) };
+    let (src, dst) = (reader.stream.as_raw_fd(), writer.stream.as_raw_fd());
+
+    let mut copied = 0;
+    loop {
+        // the pipe is always drained below, so `EAGAIN` here means the reader is not readable.
+        let n = poll_fn(|cx| reader.poll_splice(cx, src, w.as_raw_fd(), SPLICE_CHUNK)).await?;
+        if n == 0 {
+            return Ok(copied);
+        }
+
+        let mut remaining = n;
+        while remaining > 0 {
+            let m = poll_fn(|cx| writer.poll_splice(cx, r.as_raw_fd(), dst, remaining)).await?;
+            if m == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            remaining -= m;
+        }
+        copied += n as u64;
+    }
+}
+
 impl From<StdTcpStream> for TcpStream {
     fn from(value: StdTcpStream) -> Self {
         let reactor = get_reactor();
-        reactor.borrow_mut().add(value.as_raw_fd());
+        reactor.add(value.as_raw_fd());
         Self { stream: value }
     }
 }
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
-        let reactor = get_reactor();
-        reactor.borrow_mut().delete(self.stream.as_raw_fd());
+        // tasks left over are dropped with their executor after `block_on` returns, the reactor
+        // goes away together with them then.
+        if let Some(reactor) = try_get_reactor() {
+            reactor.delete(self.stream.as_raw_fd());
+        }
     }
 }
 
@@ -96,9 +467,24 @@ impl AsyncRead for TcpStream {
             Ok(n) => Poll::Ready(Ok(n)),
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                 let reactor = get_reactor();
-                reactor
-                    .borrow_mut()
-                    .interest_readable(self.stream.as_raw_fd(), cx);
+                reactor.interest_readable(self.stream.as_raw_fd(), cx);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    fn poll_read_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        // `read_vectored` of std `TcpStream` is a single `readv(2)` call.
+        match self.stream.read_vectored(bufs) {
+            Ok(n) => Poll::Ready(Ok(n)),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                let reactor = get_reactor();
+                reactor.interest_readable(self.stream.as_raw_fd(), cx);
                 Poll::Pending
             }
             Err(e) => Poll::Ready(Err(e)),
@@ -116,9 +502,24 @@ impl AsyncWrite for TcpStream {
             Ok(n) => Poll::Ready(Ok(n)),
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                 let reactor = get_reactor();
-                reactor
-                    .borrow_mut()
-                    .interest_writable(self.stream.as_raw_fd(), cx);
+                reactor.interest_writable(self.stream.as_raw_fd(), cx);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        // `write_vectored` of std `TcpStream` is a single `writev(2)` call.
+        match self.stream.write_vectored(bufs) {
+            Ok(n) => Poll::Ready(Ok(n)),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                let reactor = get_reactor();
+                reactor.interest_writable(self.stream.as_raw_fd(), cx);
                 Poll::Pending
             }
             Err(e) => Poll::Ready(Err(e)),
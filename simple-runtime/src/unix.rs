@@ -0,0 +1,214 @@
+use std::io::{self, IoSlice, IoSliceMut};
+use std::mem;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{
+    SocketAddr, UnixListener as StdUnixListener, UnixStream as StdUnixStream,
+};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::future::poll_fn;
+use futures::{ready, AsyncRead, AsyncWrite, Stream};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr};
+
+use crate::async_fd::AsyncFd;
+use crate::io::{poll_read_fd, poll_write_fd};
+
+pub struct UnixListener {
+    listener: AsyncFd<StdUnixListener>,
+}
+
+impl UnixListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_std(StdUnixListener::bind(path)?)
+    }
+
+    /// register a listener created elsewhere, e.g. received with [`UnixStream::recv_fds`].
+    pub fn from_std(listener: StdUnixListener) -> io::Result<Self> {
+        Ok(Self {
+            listener: AsyncFd::new(listener)?,
+        })
+    }
+
+    /// deregister the listener from the reactor, it stays in nonblocking mode.
+    pub fn into_std(self) -> StdUnixListener {
+        self.listener.into_inner()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.get_ref().local_addr()
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Stream for UnixListener {
+    type Item = io::Result<(UnixStream, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.listener.get_ref().accept() {
+                Ok((stream, addr)) => {
+                    return Poll::Ready(Some(UnixStream::from_std(stream).map(|s| (s, addr))))
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(self.listener.poll_read_ready(cx))?;
+                }
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
+
+pub struct UnixStream {
+    stream: AsyncFd<StdUnixStream>,
+}
+
+impl UnixStream {
+    /// Connect to the socket at `path`.
+    ///
+    /// Connecting a unix socket completes or fails at once, it never waits for the peer.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_std(StdUnixStream::connect(path)?)
+    }
+
+    /// Create a pair of connected sockets.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = StdUnixStream::pair()?;
+        Ok((Self::from_std(a)?, Self::from_std(b)?))
+    }
+
+    pub fn from_std(stream: StdUnixStream) -> io::Result<Self> {
+        Ok(Self {
+            stream: AsyncFd::new(stream)?,
+        })
+    }
+
+    /// deregister the stream from the reactor, it stays in nonblocking mode.
+    pub fn into_std(self) -> StdUnixStream {
+        self.stream.into_inner()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.get_ref().local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.get_ref().peer_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.get_ref().shutdown(how)
+    }
+
+    /// Send `data` together with the fds as `SCM_RIGHTS` ancillary data, returns the number of
+    /// bytes of `data` sent.
+    ///
+    /// The fds are duplicated into the receiver, the ones here stay open. `data` must not be
+    /// empty, or the fds won't be delivered on a stream socket.
+    pub async fn send_fds(&self, data: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        let iov = [IoSlice::new(data)];
+        let cmsgs = [ControlMessage::ScmRights(fds)];
+        self.stream
+            .write_with(|s| {
+                sendmsg::<UnixAddr>(s.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None)
+                    .map_err(Into::into)
+            })
+            .await
+    }
+
+    /// Receive data into `buf`, and at most `max_fds` fds sent with [`UnixStream::send_fds`].
+    ///
+    /// The fds received are owned by the caller and close on exec. If the sender sent more than
+    /// `max_fds`, the rest are closed by the kernel.
+    pub async fn recv_fds(
+        &self,
+        buf: &mut [u8],
+        max_fds: usize,
+    ) -> io::Result<(usize, Vec<OwnedFd>)> {
+        // SAFETY: `CMSG_SPACE` only computes the size
+        let space = unsafe { libc::CMSG_SPACE((max_fds * mem::size_of::<RawFd>()) as u32) };
+        let mut cmsg_buffer = Vec::with_capacity(space as usize);
+
+        poll_fn(|cx| loop {
+            let mut iov = [IoSliceMut::new(buf)];
+            let flags = MsgFlags::MSG_CMSG_CLOEXEC;
+            match recvmsg::<UnixAddr>(
+                self.stream.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg_buffer),
+                flags,
+            ) {
+                Ok(msg) => {
+                    let mut fds = vec![];
+                    for cmsg in msg.cmsgs() {
+                        if let ControlMessageOwned::ScmRights(raw) = cmsg {
+                            // SAFETY: the fds are just installed into this process by the kernel.
+                            fds.extend(
+                                raw.into_iter()
+                                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                            );
+                        }
+                    }
+                    return Poll::Ready(Ok((msg.bytes, fds)));
+                }
+                Err(Errno::EAGAIN) => {
+                    ready!(self.stream.poll_read_ready(cx))?;
+                }
+                Err(e) => return Poll::Ready(Err(e.into())),
+            }
+        })
+        .await
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl AsyncRead for UnixStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        poll_read_fd(&self.stream, cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        poll_write_fd(&self.stream, cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        poll_write_fd(&self.stream, cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shutdown(Shutdown::Write)?;
+        Poll::Ready(Ok(()))
+    }
+}
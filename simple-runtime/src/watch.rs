@@ -0,0 +1,277 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{ready, Stream};
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+
+use crate::async_fd::AsyncFd;
+
+/// A file system event, the path is the watched path joined with the name of the entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Create(PathBuf),
+    Modify(PathBuf),
+    Delete(PathBuf),
+    /// moved inside the watched paths, the `IN_MOVED_FROM` and `IN_MOVED_TO` pair is matched by
+    /// its cookie.
+    ///
+    /// A pair split across two reads of the inotify fd can't be matched, it is reported as a
+    /// [`Event::MovedOut`] followed by a [`Event::MovedIn`].
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// moved from a watched directory to somewhere not watched, the watches under it are removed.
+    MovedOut(PathBuf),
+    /// moved from somewhere not watched into a watched directory.
+    MovedIn(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecursiveMode {
+    /// watch the directory and all its subdirectories, including the ones created later.
+    Recursive,
+    /// watch the file or only the direct entries of the directory.
+    NonRecursive,
+}
+
+struct Watch {
+    path: PathBuf,
+    recursive: bool,
+}
+
+/// Watch files and directories with inotify, the events are received as a [`Stream`].
+pub struct Watcher {
+    inotify: Inotify,
+    fd: AsyncFd<OwnedFd>,
+    watches: HashMap<WatchDescriptor, Watch>,
+    events: VecDeque<io::Result<Event>>,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        // SAFETY: the fd is just created by `inotify_init1`, `Inotify` doesn't close it.
+        let fd = unsafe { OwnedFd::from_raw_fd(inotify.as_raw_fd()) };
+
+        Ok(Self {
+            inotify,
+            fd: AsyncFd::new(fd)?,
+            watches: Default::default(),
+            events: Default::default(),
+        })
+    }
+
+    /// Start watching `path`, a file or a directory.
+    pub fn watch(&mut self, path: impl AsRef<Path>, mode: RecursiveMode) -> io::Result<()> {
+        let path = path.as_ref();
+        let recursive = mode == RecursiveMode::Recursive && path.is_dir();
+        self.add_watch(path, recursive)?;
+        if recursive {
+            self.watch_subdirs(path, &mut |_| {})?;
+        }
+        Ok(())
+    }
+
+    /// Stop watching `path`, including its subdirectories if it was watched recursively.
+    pub fn unwatch(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let wds: Vec<_> = self
+            .watches
+            .iter()
+            .filter(|(_, w)| w.path == path || (w.recursive && w.path.starts_with(path)))
+            .map(|(wd, _)| *wd)
+            .collect();
+
+        if wds.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "path is not watched",
+            ));
+        }
+        self.rm_watches(wds)
+    }
+
+    fn rm_watches(&mut self, wds: Vec<WatchDescriptor>) -> io::Result<()> {
+        for wd in wds {
+            self.watches.remove(&wd);
+            // the watch is removed by the kernel already if the path was deleted
+            match self.inotify.rm_watch(wd) {
+                Ok(()) | Err(Errno::EINVAL) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn add_watch(&mut self, path: &Path, recursive: bool) -> io::Result<()> {
+        let mask = AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_DELETE_SELF
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_MOVED_TO;
+        let wd = self.inotify.add_watch(path, mask)?;
+        self.watches.insert(
+            wd,
+            Watch {
+                path: path.to_path_buf(),
+                recursive,
+            },
+        );
+        Ok(())
+    }
+
+    /// watch all subdirectories of `dir` recursively, `found` is called with each entry in them.
+    fn watch_subdirs(&mut self, dir: &Path, found: &mut dyn FnMut(&Path)) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            found(&path);
+            if entry.file_type()?.is_dir() {
+                self.add_watch(&path, true)?;
+                self.watch_subdirs(&path, found)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert raw inotify events to [`Event`]s.
+    ///
+    /// A rename emits `IN_MOVED_FROM` and `IN_MOVED_TO` with the same cookie next to each other,
+    /// so they are paired within one read, the unpaired ones are moves out of or into the
+    /// watched paths.
+    fn push_events(&mut self, raw: Vec<InotifyEvent>) {
+        // the cookie, the path, and whether it is a directory
+        let mut moved_from: Option<(u32, PathBuf, bool)> = None;
+
+        for event in raw {
+            if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                self.events
+                    .push_back(Err(io::Error::other("inotify event queue overflowed")));
+                continue;
+            }
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                self.watches.remove(&event.wd);
+                continue;
+            }
+            let Some(watch) = self.watches.get(&event.wd) else {
+                continue;
+            };
+            let recursive = watch.recursive;
+            let path = match &event.name {
+                Some(name) => watch.path.join(name),
+                None => watch.path.clone(),
+            };
+            let is_dir = event.mask.contains(AddWatchFlags::IN_ISDIR);
+
+            if event.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+                if let Some((_, from, was_dir)) = moved_from.replace((event.cookie, path, is_dir)) {
+                    self.moved_out(from, was_dir);
+                }
+                continue;
+            }
+
+            if let Some((cookie, from, was_dir)) = moved_from.take() {
+                if event.mask.contains(AddWatchFlags::IN_MOVED_TO) && cookie == event.cookie {
+                    if recursive && is_dir {
+                        self.rewatch(&from, &path);
+                    }
+                    self.events.push_back(Ok(Event::Rename { from, to: path }));
+                    continue;
+                }
+                self.moved_out(from, was_dir);
+            }
+
+            if event.mask.contains(AddWatchFlags::IN_MOVED_TO) {
+                if recursive && is_dir {
+                    self.watch_new_dir(&path);
+                }
+                self.events.push_back(Ok(Event::MovedIn(path)));
+            } else if event.mask.contains(AddWatchFlags::IN_CREATE) {
+                self.events.push_back(Ok(Event::Create(path.clone())));
+                if recursive && is_dir {
+                    self.watch_new_dir(&path);
+                }
+            } else if event.mask.contains(AddWatchFlags::IN_MODIFY) {
+                self.events.push_back(Ok(Event::Modify(path)));
+            } else if event.mask.contains(AddWatchFlags::IN_DELETE)
+                || event.mask.contains(AddWatchFlags::IN_DELETE_SELF)
+            {
+                self.events.push_back(Ok(Event::Delete(path)));
+            }
+        }
+
+        if let Some((_, from, was_dir)) = moved_from {
+            self.moved_out(from, was_dir);
+        }
+    }
+
+    /// The watches under a directory moved out would report its events under the old paths, so
+    /// they are removed.
+    fn moved_out(&mut self, from: PathBuf, is_dir: bool) {
+        if is_dir {
+            let wds: Vec<_> = self
+                .watches
+                .iter()
+                .filter(|(_, w)| w.path.starts_with(&from))
+                .map(|(wd, _)| *wd)
+                .collect();
+            if let Err(e) = self.rm_watches(wds) {
+                self.events.push_back(Err(e));
+            }
+        }
+        self.events.push_back(Ok(Event::MovedOut(from)));
+    }
+
+    /// Watch a directory just created or moved in, and report the entries created in it before
+    /// the watch was added.
+    fn watch_new_dir(&mut self, dir: &Path) {
+        if let Err(e) = self.add_watch(dir, true) {
+            self.events.push_back(Err(e));
+            return;
+        }
+        let mut created = vec![];
+        if let Err(e) = self.watch_subdirs(dir, &mut |p| created.push(p.to_path_buf())) {
+            self.events.push_back(Err(e));
+        }
+        self.events
+            .extend(created.into_iter().map(|p| Ok(Event::Create(p))));
+    }
+
+    /// the watches under a renamed directory keep their descriptors, only the paths change.
+    fn rewatch(&mut self, from: &Path, to: &Path) {
+        for watch in self.watches.values_mut() {
+            if let Ok(rest) = watch.path.strip_prefix(from) {
+                watch.path = to.join(rest);
+            }
+        }
+    }
+}
+
+impl Stream for Watcher {
+    type Item = io::Result<Event>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+
+            match this.inotify.read_events() {
+                Ok(raw) => this.push_events(raw),
+                Err(Errno::EAGAIN) => {
+                    ready!(this.fd.poll_read_ready(cx))?;
+                }
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            }
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use std::cell::Cell;
+use std::io;
+use std::iter;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use futures::future::BoxFuture;
+use futures::task::{waker_ref, ArcWake};
+use futures::{Future, FutureExt};
+use scoped_tls::scoped_thread_local;
+use waker_fn::waker_fn;
+
+use crate::reactor::{Notifier, Reactor, ReactorMode};
+
+scoped_thread_local!(static CURRENT: Current);
+
+/// Returns the reactor of the work-stealing runtime the current thread belongs to.
+pub(crate) fn current_reactor() -> Option<Arc<Reactor>> {
+    if CURRENT.is_set() {
+        CURRENT.with(|c| Some(c.shared.reactor.clone()))
+    } else {
+        None
+    }
+}
+
+const SCHEDULED: usize = 0b01;
+const RUNNING: usize = 0b10;
+
+struct Task {
+    // the waker may be called from any thread, so the future is guarded by a `Mutex` even though
+    // only one worker polls it at a time.
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    state: AtomicUsize,
+    // a weak reference, otherwise the reactor holding wakers and the tasks would form a cycle.
+    shared: Weak<Shared>,
+}
+
+impl Task {
+    fn run(self: Arc<Self>) {
+        self.state.store(RUNNING, Ordering::SeqCst);
+
+        let waker = waker_ref(&self);
+        let cx = &mut Context::from_waker(&waker);
+        let mut future = self.future.lock().unwrap();
+        let done = match future.as_mut() {
+            Some(fut) => fut.as_mut().poll(cx).is_ready(),
+            None => true,
+        };
+        if done {
+            // keep `RUNNING` set, so the wakers left are no-ops from now on
+            *future = None;
+            return;
+        }
+        drop(future);
+
+        // woken while running, schedule it again
+        if self.state.fetch_and(!RUNNING, Ordering::SeqCst) == SCHEDULED | RUNNING {
+            schedule(self);
+        }
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if arc_self.state.fetch_or(SCHEDULED, Ordering::SeqCst) == 0 {
+            schedule(arc_self.clone());
+        }
+    }
+}
+
+/// Push the task onto the LIFO slot of the current worker if it belongs to the same runtime,
+/// otherwise onto the global injector, then wake up an idle worker to run or steal it.
+fn schedule(task: Arc<Task>) {
+    let Some(shared) = task.shared.upgrade() else {
+        // the runtime is gone, the task is dropped
+        return;
+    };
+
+    let task = if CURRENT.is_set() {
+        CURRENT.with(|c| match &c.worker {
+            Some(w) if Arc::ptr_eq(&c.shared, &shared) => {
+                // the task just woken is likely to use data that is hot in cache, run it next
+                if let Some(prev) = w.lifo.replace(Some(task)) {
+                    w.local.push(prev);
+                }
+                None
+            }
+            _ => Some(task),
+        })
+    } else {
+        Some(task)
+    };
+
+    if let Some(task) = task {
+        shared.injector.push(task);
+    }
+    shared.unpark_one();
+}
+
+struct Shared {
+    injector: Injector<Arc<Task>>,
+    stealers: Vec<Stealer<Arc<Task>>>,
+    reactor: Arc<Reactor>,
+    notifier: Notifier,
+    // held by the worker blocking in `Reactor::wait`
+    driver: Mutex<()>,
+    sleep: Mutex<()>,
+    condvar: Condvar,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn has_work(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+
+    /// wake up a worker sleeping on the condvar and the one driving the reactor, if any.
+    fn unpark_one(&self) {
+        self.notifier.notify();
+        let _sleep = self.sleep.lock().unwrap();
+        self.condvar.notify_one();
+    }
+
+    fn unpark_all(&self) {
+        self.notifier.notify();
+        let _sleep = self.sleep.lock().unwrap();
+        self.condvar.notify_all();
+    }
+
+    fn spawn<F>(self: &Arc<Self>, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future.boxed())),
+            state: AtomicUsize::new(SCHEDULED),
+            shared: Arc::downgrade(self),
+        });
+        schedule(task);
+    }
+}
+
+struct WorkerCx {
+    index: usize,
+    local: Worker<Arc<Task>>,
+    lifo: Cell<Option<Arc<Task>>>,
+}
+
+struct Current {
+    shared: Arc<Shared>,
+    // `None` for a thread blocking in `Runtime::block_on`
+    worker: Option<WorkerCx>,
+}
+
+impl WorkerCx {
+    fn find_task(&self, shared: &Shared) -> Option<Arc<Task>> {
+        if let Some(task) = self.lifo.take() {
+            return Some(task);
+        }
+        if let Some(task) = self.local.pop() {
+            return Some(task);
+        }
+
+        loop {
+            let mut retry = false;
+
+            // steal from the injector first, then from siblings starting after ourselves
+            let siblings = (1..shared.stealers.len())
+                .map(|i| &shared.stealers[(self.index + i) % shared.stealers.len()]);
+            let steals = iter::once(shared.injector.steal_batch_and_pop(&self.local))
+                .chain(siblings.map(|s| s.steal_batch_and_pop(&self.local)));
+
+            for steal in steals {
+                match steal {
+                    Steal::Success(task) => return Some(task),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+            }
+
+            if !retry {
+                return None;
+            }
+        }
+    }
+
+    /// Park the worker when there is nothing to run.
+    ///
+    /// The first worker that gets here drives the shared reactor, the others sleep on the
+    /// condvar. Anything that schedules a task notifies both.
+    fn park(&self, shared: &Shared) {
+        if let Ok(driver) = shared.driver.try_lock() {
+            if !shared.has_work() && !shared.shutdown.load(Ordering::Acquire) {
+                shared.reactor.wait();
+            }
+            drop(driver);
+            // we may be busy running tasks for a while, hand the reactor over to a sleeper
+            let _sleep = shared.sleep.lock().unwrap();
+            shared.condvar.notify_one();
+            return;
+        }
+
+        let sleep = shared.sleep.lock().unwrap();
+        if !shared.has_work() && !shared.shutdown.load(Ordering::Acquire) {
+            drop(shared.condvar.wait(sleep).unwrap());
+        }
+    }
+}
+
+fn run_worker(shared: Arc<Shared>, worker: WorkerCx) {
+    let current = Current {
+        shared,
+        worker: Some(worker),
+    };
+
+    CURRENT.set(&current, || {
+        let shared = &current.shared;
+        let worker = current.worker.as_ref().unwrap();
+        while !shared.shutdown.load(Ordering::Acquire) {
+            match worker.find_task(shared) {
+                Some(task) => task.run(),
+                None => worker.park(shared),
+            }
+        }
+    });
+}
+
+/// Configure and launch a work-stealing [`Runtime`].
+///
+/// Unlike [`runtime::Runtime`](crate::runtime::Runtime), tasks may move between threads, so
+/// they must be `Send`. Each worker has a local deque and a LIFO slot for the task it just woke,
+/// idle workers steal from the global injector and their siblings. A single reactor is shared
+/// by all workers and driven by whichever one is parked.
+pub struct Builder {
+    worker_threads: usize,
+    thread_name: String,
+    reactor_mode: ReactorMode,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            worker_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            thread_name: "simple-runtime-worker".into(),
+            reactor_mode: ReactorMode::default(),
+        }
+    }
+
+    /// the number of worker threads, defaults to the available parallelism.
+    pub fn worker_threads(mut self, n: usize) -> Self {
+        self.worker_threads = n.max(1);
+        self
+    }
+
+    /// the thread name prefix, the worker index is appended to it.
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// how the shared reactor registers fds, defaults to edge-triggered.
+    pub fn reactor_mode(mut self, mode: ReactorMode) -> Self {
+        self.reactor_mode = mode;
+        self
+    }
+
+    pub fn build(self) -> io::Result<Runtime> {
+        let workers: Vec<_> = (0..self.worker_threads)
+            .map(|_| Worker::new_fifo())
+            .collect();
+        let reactor = Reactor::new(self.reactor_mode);
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            notifier: reactor.notifier(),
+            reactor: Arc::new(reactor),
+            driver: Mutex::new(()),
+            sleep: Mutex::new(()),
+            condvar: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let mut rt = Runtime {
+            handle: Handle {
+                shared: shared.clone(),
+            },
+            threads: Vec::with_capacity(self.worker_threads),
+        };
+        for (index, local) in workers.into_iter().enumerate() {
+            let shared = shared.clone();
+            let worker = WorkerCx {
+                index,
+                local,
+                lifo: Cell::new(None),
+            };
+            // on error the runtime is dropped, which stops the workers already started
+            let thread = thread::Builder::new()
+                .name(format!("{}-{index}", self.thread_name))
+                .spawn(move || run_worker(shared, worker))?;
+            rt.threads.push(thread);
+        }
+
+        Ok(rt)
+    }
+}
+
+/// A cloneable, `Send` handle to spawn tasks onto a work-stealing [`Runtime`].
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.shared.spawn(future);
+    }
+}
+
+/// Spawn a task onto the work-stealing runtime the current thread belongs to.
+///
+/// # Panics
+///
+/// This function will panic if it is called outside a worker or `Runtime::block_on`.
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    CURRENT.with(|c| c.shared.spawn(future));
+}
+
+/// A multi-threaded work-stealing runtime, see [`Builder`].
+pub struct Runtime {
+    handle: Handle,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Runtime {
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.handle.spawn(future);
+    }
+
+    /// Block the current thread until `future` is ready.
+    ///
+    /// The future is polled on the current thread, not on a worker, so it doesn't need to be
+    /// `Send`, and it can use the runtime's IO types and [`spawn`].
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let thread = thread::current();
+        let waker = waker_fn(move || thread.unpark());
+        let cx = &mut Context::from_waker(&waker);
+        let mut future = pin!(future);
+
+        let current = Current {
+            shared: self.handle.shared.clone(),
+            worker: None,
+        };
+        CURRENT.set(&current, || loop {
+            if let Poll::Ready(t) = future.as_mut().poll(cx) {
+                break t;
+            }
+            thread::park();
+        })
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        let shared = &self.handle.shared;
+        shared.shutdown.store(true, Ordering::Release);
+        shared.unpark_all();
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}